@@ -0,0 +1,691 @@
+//! A [Debug Adapter Protocol](https://microsoft.github.io/debug-adapter-protocol/) server for
+//! scripts running in a `State`, available with the `dap` feature.
+//!
+//! The server is built on the hook dispatcher and the debug interface of `State`. It supports
+//! line breakpoints, pausing, stepping, stack traces, inspection of locals, upvalues, globals and
+//! tables, and evaluation of expressions in the context of a stack frame.
+//!
+//! Messages are exchanged with the client on background threads, so the client can be served over
+//! any reader and writer. The script runs on the thread which owns the state; while it is stopped,
+//! requests are handled from within the hook.
+//!
+//! ```ignore
+//! let listener = TcpListener::bind("127.0.0.1:4711").unwrap();
+//! let session = DebugSession::accept(&mut state, &listener).unwrap();
+//! session.wait_for_configuration(&mut state);
+//! state.do_file::<(), _>("main.lua").unwrap();
+//! session.close(&mut state);
+//! ```
+
+mod transport;
+
+use std::io;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+
+use serde_json::Value;
+
+use ffi;
+use ::{State, HookMask, HookEvent, HookId, LuaType, LuaIndex, RelIndex, LuaCallResults,
+       LoadMode};
+
+/// The id of the only thread reported to the client.
+const THREAD_ID: u64 = 1;
+
+/// A connection to a debugger client.
+///
+/// Creating a session adds a line hook to the state, which checks for breakpoints and handles
+/// requests from the client. The hook removes itself once the client disconnects or the session is
+/// closed.
+pub struct DebugSession {
+    debugger: Rc<RefCell<Debugger>>,
+}
+
+impl DebugSession {
+    /// Creates a session which receives requests from `reader` and sends responses and events to
+    /// `writer`.
+    pub fn new<R, W>(state: &mut State, reader: R, writer: W) -> DebugSession
+        where R: io::Read + Send + 'static,
+              W: io::Write + Send + 'static
+    {
+        let (requests, messages) = transport::spawn(reader, writer);
+        let debugger = Rc::new(RefCell::new(Debugger {
+            requests: requests,
+            messages: messages,
+            seq: 1,
+            hook: None,
+            breakpoints: HashMap::new(),
+            step: Step::None,
+            pause_requested: false,
+            configured: false,
+            disconnected: false,
+            stopped: false,
+            variables: Vec::new(),
+        }));
+        let hook_debugger = debugger.clone();
+        let mask = HookMask { line: true, ..HookMask::default() };
+        let id = state.add_hook(mask, move |state, event| {
+            // The debugger is already borrowed while it runs code for the client
+            let mut debugger = match hook_debugger.try_borrow_mut() {
+                Ok(debugger) => debugger,
+                Err(_) => return Ok(()),
+            };
+            if let HookEvent::Line(line) = event {
+                debugger.on_line(state, line);
+            }
+            if debugger.disconnected {
+                if let Some(id) = debugger.hook.take() {
+                    state.remove_hook(id);
+                }
+            }
+            Ok(())
+        });
+        debugger.borrow_mut().hook = Some(id);
+        DebugSession { debugger: debugger }
+    }
+
+    /// Creates a session which communicates over standard input and output. Scripts must not
+    /// write to standard output while the session is open, as that would corrupt the messages.
+    pub fn stdio(state: &mut State) -> DebugSession {
+        DebugSession::new(state, io::stdin(), io::stdout())
+    }
+
+    /// Waits for a client to connect to `listener`, and creates a session which communicates
+    /// with it.
+    pub fn accept(state: &mut State, listener: &TcpListener) -> io::Result<DebugSession> {
+        let (stream, _) = try!(listener.accept());
+        let writer = try!(stream.try_clone());
+        Ok(DebugSession::new(state, stream, writer))
+    }
+
+    /// Handles requests until the client sends `configurationDone`, which it does after setting
+    /// the initial breakpoints. Call this before running the script to debug. Returns `false` if
+    /// the client disconnected.
+    pub fn wait_for_configuration(&self, state: &mut State) -> bool {
+        let mut debugger = self.debugger.borrow_mut();
+        while !debugger.configured && !debugger.disconnected {
+            match debugger.requests.recv() {
+                Ok(request) => {
+                    debugger.handle(state, &request);
+                }
+                Err(_) => debugger.disconnected = true,
+            }
+        }
+        !debugger.disconnected
+    }
+
+    /// Handles the requests which have arrived since the script last ran, without blocking. The
+    /// hook only handles requests while a script is running, so call this periodically if the
+    /// host runs scripts only occasionally.
+    pub fn process_requests(&self, state: &mut State) {
+        self.debugger.borrow_mut().poll(state);
+    }
+
+    /// Returns `true` if the client is still connected.
+    pub fn is_connected(&self) -> bool {
+        !self.debugger.borrow().disconnected
+    }
+
+    /// Sends text to the client's debug console, for example to forward the output of `print`.
+    pub fn output(&self, text: &str) {
+        self.debugger.borrow_mut().send_event("output", json!({
+            "category": "stdout",
+            "output": text,
+        }));
+    }
+
+    /// Tells the client that the debugged program has finished, and removes the hook.
+    pub fn close(self, state: &mut State) {
+        let mut debugger = self.debugger.borrow_mut();
+        if let Some(id) = debugger.hook.take() {
+            state.remove_hook(id);
+        }
+        debugger.finish();
+    }
+}
+
+impl Drop for DebugSession {
+    fn drop(&mut self) {
+        self.debugger.borrow_mut().finish();
+    }
+}
+
+/// How execution continues after the script was stopped.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Step {
+    /// Run until a breakpoint is hit or a pause is requested.
+    None,
+    /// Stop at the next line.
+    In,
+    /// Stop at the next line of a function at or above the given stack depth.
+    Over(u32),
+    /// Stop at the next line of a function above the given stack depth.
+    Out(u32),
+}
+
+/// What a variable reference refers to. References are only valid while the script is stopped.
+#[derive(Copy, Clone, Debug)]
+enum Variables {
+    Locals(u32),
+    Upvalues(u32),
+    Globals,
+    /// A table stored in the handles table.
+    Table(i64),
+}
+
+/// What the script does after a request was handled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Action {
+    Stay,
+    Resume,
+}
+
+struct Debugger {
+    requests: Receiver<Value>,
+    messages: Sender<Value>,
+    seq: u64,
+    hook: Option<HookId>,
+    /// Breakpoint lines by source path.
+    breakpoints: HashMap<String, Vec<u32>>,
+    step: Step,
+    pause_requested: bool,
+    configured: bool,
+    disconnected: bool,
+    stopped: bool,
+    variables: Vec<Variables>,
+}
+
+impl Debugger {
+    fn on_line(&mut self, state: &mut State, line: u32) {
+        self.poll(state);
+        if self.disconnected {
+            return;
+        }
+        let reason = if self.pause_requested {
+            Some("pause")
+        } else if self.is_step_done(state) {
+            Some("step")
+        } else if self.is_breakpoint(state, line) {
+            Some("breakpoint")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.stop(state, reason);
+        }
+    }
+
+    fn is_step_done(&self, state: &State) -> bool {
+        match self.step {
+            Step::None => false,
+            Step::In => true,
+            Step::Over(depth) => state.stack_depth() <= depth,
+            Step::Out(depth) => state.stack_depth() < depth,
+        }
+    }
+
+    fn is_breakpoint(&self, state: &mut State, line: u32) -> bool {
+        if !self.breakpoints.values().any(|lines| lines.contains(&line)) {
+            return false;
+        }
+        let info = match state.with_stack_level(0, |state, ar| state.get_info(ar, "S")) {
+            Some(info) => info,
+            None => return false,
+        };
+        if !info.source.starts_with('@') {
+            return false;
+        }
+        self.breakpoints
+            .iter()
+            .any(|(path, lines)| lines.contains(&line) && paths_match(path, &info.source[1..]))
+    }
+
+    /// Stops the script and handles requests until the client resumes it.
+    fn stop(&mut self, state: &mut State, reason: &str) {
+        self.stopped = true;
+        self.pause_requested = false;
+        self.step = Step::None;
+        self.send_event("stopped", json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        }));
+        loop {
+            match self.requests.recv() {
+                Ok(request) => {
+                    if self.handle(state, &request) == Action::Resume {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    self.disconnected = true;
+                    break;
+                }
+            }
+        }
+        self.stopped = false;
+        self.variables.clear();
+        state.get_internal_registry();
+        state.push_nil();
+        state.set_field(LuaIndex::Stack(-2), "variables");
+        state.pop(1);
+    }
+
+    /// Handles the requests which have already arrived.
+    fn poll(&mut self, state: &mut State) {
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => {
+                    self.handle(state, &request);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, state: &mut State, request: &Value) -> Action {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let top = state.get_top();
+        let mut action = Action::Stay;
+        let result = match command {
+            "initialize" => {
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }))
+            }
+            "launch" | "attach" | "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                self.configured = true;
+                Ok(json!({}))
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "pause" => {
+                self.pause_requested = true;
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.disconnected = true;
+                action = Action::Resume;
+                Ok(json!({}))
+            }
+            "stackTrace" | "scopes" | "variables" | "continue" | "next" | "stepIn" |
+            "stepOut" if !self.stopped => Err("the script is not stopped".to_string()),
+            "stackTrace" => Ok(self.stack_trace(state)),
+            "scopes" => self.scopes(state, args),
+            "variables" => self.variables(state, args),
+            "evaluate" => self.evaluate(state, args),
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let depth = state.stack_depth();
+                self.step = match command {
+                    "next" => Step::Over(depth),
+                    "stepIn" => Step::In,
+                    "stepOut" => Step::Out(depth),
+                    _ => Step::None,
+                };
+                action = Action::Resume;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+        state.set_top(top);
+        self.respond(request, result);
+        if command == "initialize" {
+            self.send_event("initialized", json!({}));
+        }
+        action
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let source = &args["source"];
+        let path = source["path"].as_str().or_else(|| source["name"].as_str()).unwrap_or("");
+        let lines: Vec<u32> = match args["breakpoints"].as_array() {
+            Some(breakpoints) => {
+                breakpoints.iter().filter_map(|bp| bp["line"].as_u64()).map(|l| l as u32).collect()
+            }
+            None => Vec::new(),
+        };
+        let breakpoints: Vec<Value> = lines.iter()
+            .map(|&line| json!({ "verified": true, "line": line }))
+            .collect();
+        if lines.is_empty() {
+            self.breakpoints.remove(path);
+        } else {
+            self.breakpoints.insert(path.to_string(), lines);
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&mut self, state: &mut State) -> Value {
+        let mut frames = Vec::new();
+        let mut level = 0;
+        loop {
+            let info = match state.with_stack_level(level, |state, ar| state.get_info(ar, "Sln")) {
+                Some(info) => info,
+                None => break,
+            };
+            let name = match info.name {
+                Some(ref name) => name.clone(),
+                None if info.what == "main" => "main chunk".to_string(),
+                None if info.what == "C" => "[native]".to_string(),
+                None => format!("function <{}:{}>", info.short_src, info.line_defined),
+            };
+            let mut frame = json!({
+                "id": level + 1,
+                "name": name,
+                "line": info.current_line.unwrap_or(0),
+                "column": 1,
+            });
+            if info.what != "C" {
+                frame["source"] = source_json(&info.source, &info.short_src);
+            }
+            frames.push(frame);
+            level += 1;
+        }
+        json!({ "stackFrames": frames, "totalFrames": level })
+    }
+
+    fn scopes(&mut self, state: &mut State, args: &Value) -> Result<Value, String> {
+        let level = try!(frame_level(state, &args["frameId"]));
+        let locals = self.variables_reference(Variables::Locals(level));
+        let upvalues = self.variables_reference(Variables::Upvalues(level));
+        let globals = self.variables_reference(Variables::Globals);
+        Ok(json!({
+            "scopes": [
+                { "name": "Locals", "variablesReference": locals, "expensive": false },
+                { "name": "Upvalues", "variablesReference": upvalues, "expensive": false },
+                { "name": "Globals", "variablesReference": globals, "expensive": true },
+            ]
+        }))
+    }
+
+    fn variables(&mut self, state: &mut State, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+        let variables = match self.variables.get(reference.wrapping_sub(1)) {
+            Some(&variables) => variables,
+            None => return Err("invalid variable reference".to_string()),
+        };
+        let mut result = Vec::new();
+        match variables {
+            Variables::Locals(level) => {
+                let found = state.with_stack_level(level, |state, ar| {
+                    let mut n = 1;
+                    while let Some(name) = state.get_local(ar, n) {
+                        // Skip internal variables such as loop control variables
+                        if !name.starts_with('(') {
+                            let variable = self.describe_variable(state, name);
+                            result.push(variable);
+                        }
+                        state.pop(1);
+                        n += 1;
+                    }
+                });
+                try!(found.ok_or("invalid frame".to_string()));
+            }
+            Variables::Upvalues(level) => {
+                let pushed = state.with_stack_level(level, |state, ar| {
+                    state.get_info(ar, "f");
+                });
+                try!(pushed.ok_or("invalid frame".to_string()));
+                let function = state.abs_index(LuaIndex::Stack(-1));
+                let mut n = 1;
+                while let Some(name) = state.get_upvalue(function, n) {
+                    let name = if name.is_empty() { format!("({})", n) } else { name };
+                    let variable = self.describe_variable(state, name);
+                    result.push(variable);
+                    state.pop(1);
+                    n += 1;
+                }
+            }
+            Variables::Globals => {
+                state.raw_get_i(LuaIndex::Registry, ffi::LUA_RIDX_GLOBALS as i64);
+                self.describe_fields(state, &mut result);
+            }
+            Variables::Table(handle) => {
+                push_handles(state);
+                state.raw_get_i(LuaIndex::Stack(-1), handle);
+                self.describe_fields(state, &mut result);
+            }
+        }
+        Ok(json!({ "variables": result }))
+    }
+
+    fn evaluate(&mut self, state: &mut State, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().unwrap_or("");
+        let source = format!("return {}", expression);
+        if args["frameId"].is_null() {
+            state.raw_get_i(LuaIndex::Registry, ffi::LUA_RIDX_GLOBALS as i64);
+        } else {
+            let level = try!(frame_level(state, &args["frameId"]));
+            try!(push_frame_env(state, level));
+        }
+        let env = state.abs_index(LuaIndex::Stack(-1));
+        try!(state.load_string_with_env(&source, "=(eval)", env, LoadMode::Text)
+            .map_err(|err| err.to_string()));
+        try!(state.call(0, LuaCallResults::Num(1)).map_err(|err| err.to_string()));
+        let (value, reference) = self.describe_value(state, LuaIndex::Stack(-1));
+        Ok(json!({
+            "result": value,
+            "type": type_name(state.type_at(LuaIndex::Stack(-1))),
+            "variablesReference": reference,
+        }))
+    }
+
+    /// Describes the value at the top of the stack as a variable called `name`.
+    fn describe_variable(&mut self, state: &mut State, name: String) -> Value {
+        let (value, reference) = self.describe_value(state, LuaIndex::Stack(-1));
+        json!({
+            "name": name,
+            "value": value,
+            "type": type_name(state.type_at(LuaIndex::Stack(-1))),
+            "variablesReference": reference,
+        })
+    }
+
+    /// Describes the fields of the table at the top of the stack, which is popped.
+    fn describe_fields(&mut self, state: &mut State, result: &mut Vec<Value>) {
+        let table = state.abs_index(LuaIndex::Stack(-1));
+        if state.is_table(table) {
+            state.push_nil();
+            while state.next(table) {
+                let name = match state.type_at(LuaIndex::Stack(-2)) {
+                    Some(LuaType::String) => {
+                        state.at::<String>(LuaIndex::Stack(-2)).unwrap_or_else(|_| "?".to_string())
+                    }
+                    _ => format!("[{}]", self.describe_value(state, LuaIndex::Stack(-2)).0),
+                };
+                let variable = self.describe_variable(state, name);
+                result.push(variable);
+                state.pop(1);
+            }
+        }
+        state.pop(1);
+    }
+
+    /// Returns a printable representation of a value, and a variable reference if it is a table.
+    /// Metamethods are not called, as they could run arbitrary code.
+    fn describe_value(&mut self, state: &mut State, idx: LuaIndex) -> (String, usize) {
+        let lua = state.as_raw_ptr();
+        let pointer = unsafe { ffi::lua_topointer(lua, idx.to_ffi()) };
+        match state.type_at(idx) {
+            None | Some(LuaType::Nil) => ("nil".to_string(), 0),
+            Some(LuaType::Boolean) => (state.at::<bool>(idx).unwrap().to_string(), 0),
+            Some(LuaType::Number) => {
+                if state.is_integer(idx) {
+                    (state.at::<i64>(idx).unwrap().to_string(), 0)
+                } else {
+                    (format!("{:?}", state.at::<f64>(idx).unwrap()), 0)
+                }
+            }
+            Some(LuaType::String) => {
+                match state.at::<String>(idx) {
+                    Ok(string) => (format!("{:?}", string), 0),
+                    Err(_) => ("(binary string)".to_string(), 0),
+                }
+            }
+            Some(LuaType::Table) => {
+                let idx = state.abs_index(idx);
+                push_handles(state);
+                let handle = state.raw_len(LuaIndex::Stack(-1)) as i64 + 1;
+                state.push_value(idx);
+                state.raw_set_i(LuaIndex::Stack(-2), handle);
+                state.pop(1);
+                let reference = self.variables_reference(Variables::Table(handle));
+                (format!("table: {:?}", pointer), reference)
+            }
+            Some(LuaType::Function) => (format!("function: {:?}", pointer), 0),
+            Some(LuaType::Userdata) |
+            Some(LuaType::LightUserdata) => (format!("userdata: {:?}", pointer), 0),
+            Some(LuaType::Thread) => (format!("thread: {:?}", pointer), 0),
+        }
+    }
+
+    fn variables_reference(&mut self, variables: Variables) -> usize {
+        self.variables.push(variables);
+        self.variables.len()
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"].clone(),
+            "command": request["command"].clone(),
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+        self.send(response);
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        if self.messages.send(message).is_err() {
+            self.disconnected = true;
+        }
+    }
+
+    /// Tells the client that the program has finished, unless it already disconnected.
+    fn finish(&mut self) {
+        if !self.disconnected {
+            self.send_event("terminated", json!({}));
+            self.disconnected = true;
+        }
+    }
+}
+
+/// Converts a frame id to a stack level.
+/// Pushes the table which holds the values referred to by variable references while the script is
+/// stopped, creating it if necessary.
+fn push_handles(state: &mut State) {
+    state.get_internal_registry();
+    if state.get_field(LuaIndex::Stack(-1), "variables") != LuaType::Table {
+        state.pop(1);
+        state.new_table();
+        state.push_value(LuaIndex::Stack(-1));
+        state.set_field(LuaIndex::Stack(-3), "variables");
+    }
+    state.remove(RelIndex(-2));
+}
+
+fn frame_level(state: &State, frame_id: &Value) -> Result<u32, String> {
+    match frame_id.as_u64() {
+        Some(id) if id > 0 && id <= state.stack_depth() as u64 => Ok(id as u32 - 1),
+        _ => Err("invalid frame".to_string()),
+    }
+}
+
+/// Pushes an environment for evaluating expressions in a stack frame. It contains the upvalues
+/// and locals of the frame, and falls back to the global table. Assignments to variables are not
+/// written back to the frame.
+fn push_frame_env(state: &mut State, level: u32) -> Result<(), String> {
+    let pushed = state.with_stack_level(level, |state, ar| {
+        state.raw_get_i(LuaIndex::Registry, ffi::LUA_RIDX_GLOBALS as i64);
+        state.new_env(LuaIndex::Stack(-1));
+        let env = state.abs_index(LuaIndex::Stack(-1));
+        state.get_info(ar, "f");
+        let function = state.abs_index(LuaIndex::Stack(-1));
+        let mut n = 1;
+        while let Some(name) = state.get_upvalue(function, n) {
+            if name.is_empty() || name == "_ENV" {
+                state.pop(1);
+            } else {
+                state.set_field(env, &name);
+            }
+            n += 1;
+        }
+        state.pop(1);
+        let mut n = 1;
+        while let Some(name) = state.get_local(ar, n) {
+            if name.starts_with('(') {
+                state.pop(1);
+            } else {
+                state.set_field(env, &name);
+            }
+            n += 1;
+        }
+        state.remove(RelIndex(-2));
+    });
+    pushed.ok_or("invalid frame".to_string())
+}
+
+fn source_json(source: &str, short_src: &str) -> Value {
+    if source.starts_with('@') {
+        let path = &source[1..];
+        let name = path.rsplit(|c| c == '/' || c == '\\').next().unwrap_or(path);
+        json!({ "name": name, "path": path })
+    } else {
+        json!({ "name": short_src })
+    }
+}
+
+fn type_name(ty: Option<LuaType>) -> &'static str {
+    match ty {
+        None | Some(LuaType::Nil) => "nil",
+        Some(LuaType::Boolean) => "boolean",
+        Some(LuaType::Number) => "number",
+        Some(LuaType::String) => "string",
+        Some(LuaType::Function) => "function",
+        Some(LuaType::LightUserdata) |
+        Some(LuaType::Userdata) => "userdata",
+        Some(LuaType::Thread) => "thread",
+        Some(LuaType::Table) => "table",
+    }
+}
+
+/// Compares the path of a breakpoint with the path in a chunk name. Either may be relative, so
+/// the paths match if one is a suffix of the other.
+fn paths_match(a: &str, b: &str) -> bool {
+    let a = normalize_path(a);
+    let b = normalize_path(b);
+    a == b || a.ends_with(&format!("/{}", b)) || b.ends_with(&format!("/{}", a))
+}
+
+fn normalize_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    if path.starts_with("./") {
+        path[2..].to_string()
+    } else {
+        path
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use serde_json::{self, Value};
+
+/// Starts the threads which exchange messages with the client. Incoming messages are sent to the
+/// returned receiver, and messages sent to the returned sender are written to the client. The
+/// receiver is disconnected when the client closes the connection.
+pub fn spawn<R, W>(reader: R, writer: W) -> (Receiver<Value>, Sender<Value>)
+    where R: Read + Send + 'static,
+          W: Write + Send + 'static
+{
+    let (incoming_tx, incoming_rx) = mpsc::channel();
+    let (outgoing_tx, outgoing_rx) = mpsc::channel::<Value>();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if incoming_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut writer = writer;
+        for message in outgoing_rx {
+            if write_message(&mut writer, &message).is_err() {
+                break;
+            }
+        }
+    });
+    (incoming_rx, outgoing_tx)
+}
+
+/// Reads a message framed with a `Content-Length` header. Returns `None` at the end of the
+/// stream.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if try!(reader.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_right();
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        if name.to_lowercase() == "content-length" {
+            length = value.parse::<usize>().ok();
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length")),
+    };
+    let mut body = vec![0; length];
+    try!(reader.read_exact(&mut body));
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Writes a message framed with a `Content-Length` header.
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    try!(write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body));
+    writer.flush()
+}
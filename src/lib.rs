@@ -2,12 +2,44 @@
 
 extern crate lua53_sys as ffi;
 extern crate libc;
+#[cfg(feature = "dap")]
+#[macro_use]
+extern crate serde_json;
 mod state;
+#[cfg(feature = "dap")]
+pub mod dap;
 
 use std::{result, io, fmt, error};
 use std::string::FromUtf8Error;
 
 pub use state::*;
+pub use ffi::lua_State;
+
+/// Defines the entry point of a Lua C module, so that a crate built as a `cdylib` can be loaded
+/// with `require` by any Lua 5.3 interpreter.
+///
+/// `lua_module!(luaopen_name, open)` defines the function `luaopen_name`, which calls the native
+/// function `open` when the module is required and returns its results. The state is owned by
+/// the interpreter, so lowlua never closes it, and lowlua's internal registry table is created
+/// when it is first needed. Errors returned by `open` and panics are raised as Lua errors.
+///
+/// ```ignore
+/// fn open(state: &mut State) -> RunResult<u32> {
+///     ModuleBuilder::new().function("hello", hello).push(state);
+///     Ok(1)
+/// }
+///
+/// lua_module!(luaopen_hello, open);
+/// ```
+#[macro_export]
+macro_rules! lua_module {
+    ($name:ident, $open:expr) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(lua: *mut $crate::lua_State) -> ::std::os::raw::c_int {
+            $crate::State::open_module(lua, $open)
+        }
+    }
+}
 
 /// Type for native functions.
 ///
@@ -70,6 +102,27 @@ pub enum LuaOperator {
     Shr,
 }
 
+impl LuaOperator {
+    /// Returns `true` for operators which take a single operand.
+    fn is_unary(&self) -> bool {
+        match *self {
+            LuaOperator::Unm | LuaOperator::BNot => true,
+            _ => false,
+        }
+    }
+}
+
+/// Enum of Lua comparison operations.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LuaCompare {
+    /// Equality (==)
+    Eq,
+    /// Less than (<)
+    Lt,
+    /// Less than or equal (<=)
+    Le,
+}
+
 /// Used when calling Lua functions to specify the number of results (return values) desired to be
 /// placed on the stack after the call. `MultiRet` is unbounded, but `Num` limits them to its value.
 pub enum LuaCallResults {
@@ -87,11 +140,11 @@ pub enum LuaIndex {
 }
 
 impl LuaIndex {
-    /// Convert the index to a stack index if possible, otherwise panic.
-    pub fn to_stack(&self) -> i32 {
+    /// Convert the index to a stack index if it refers to a stack slot.
+    pub fn to_stack(&self) -> Option<i32> {
         match *self {
-            LuaIndex::Stack(val) => val,
-            _ => unreachable!(),
+            LuaIndex::Stack(val) => Some(val),
+            _ => None,
         }
     }
 
@@ -104,10 +157,117 @@ impl LuaIndex {
     }
 }
 
-/// An interned Lua string. Guaranteed not to be garbage collected, as a reference to the string
-/// is permanently stored in the registry.
-#[derive(Copy, Clone, Eq, PartialEq, Hash)]
-pub struct LuaString(usize);
+impl From<AbsIndex> for LuaIndex {
+    fn from(idx: AbsIndex) -> LuaIndex {
+        LuaIndex::Stack(idx.0)
+    }
+}
+
+impl From<RelIndex> for LuaIndex {
+    fn from(idx: RelIndex) -> LuaIndex {
+        LuaIndex::Stack(idx.0)
+    }
+}
+
+impl From<PseudoIndex> for LuaIndex {
+    fn from(idx: PseudoIndex) -> LuaIndex {
+        match idx {
+            PseudoIndex::Upvalue(n) => LuaIndex::Upvalue(n),
+            PseudoIndex::Registry => LuaIndex::Registry,
+        }
+    }
+}
+
+/// An absolute stack index, counted from the bottom of the current stack frame. The first value
+/// on the stack has index 1.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct AbsIndex(i32);
+
+impl AbsIndex {
+    /// Creates an absolute index, or returns `None` if `idx` is not positive.
+    pub fn new(idx: i32) -> Option<AbsIndex> {
+        if idx > 0 { Some(AbsIndex(idx)) } else { None }
+    }
+
+    /// Returns the raw index.
+    pub fn get(&self) -> i32 {
+        self.0
+    }
+}
+
+/// A stack index relative to the top of the stack. The value at the top has index -1.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct RelIndex(i32);
+
+impl RelIndex {
+    /// Creates a relative index, or returns `None` if `idx` is not negative or is a pseudo-index.
+    pub fn new(idx: i32) -> Option<RelIndex> {
+        if idx < 0 && idx > ffi::LUA_REGISTRYINDEX {
+            Some(RelIndex(idx))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the raw index.
+    pub fn get(&self) -> i32 {
+        self.0
+    }
+}
+
+/// A pseudo-index, which refers to a value that is accessible like a stack value but is not
+/// actually on the stack.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PseudoIndex {
+    /// An upvalue of the running native closure. The first upvalue has index 1.
+    Upvalue(u32),
+    /// The registry.
+    Registry,
+}
+
+/// An index which refers to an actual stack position, as required by positional operations such
+/// as `State::insert()` and `State::remove()`. Pseudo-indices can not be converted to this type.
+pub trait StackSlot: Copy {
+    /// Returns the raw index.
+    fn slot(&self) -> i32;
+}
+
+impl StackSlot for AbsIndex {
+    fn slot(&self) -> i32 {
+        self.0
+    }
+}
+
+impl StackSlot for RelIndex {
+    fn slot(&self) -> i32 {
+        self.0
+    }
+}
+
+/// Specifies which kinds of chunks a loading function accepts.
+///
+/// Binary chunks are not verified by Lua, and malicious bytecode can easily crash the interpreter,
+/// so `Binary` and `Both` should only be used with trusted input.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LoadMode {
+    /// Only text chunks (Lua source code) are accepted.
+    Text,
+    /// Only binary chunks (precompiled bytecode produced by `State::dump()`) are accepted.
+    Binary,
+    /// Both text and binary chunks are accepted.
+    Both,
+}
+
+impl LoadMode {
+    fn to_ffi(&self) -> *const libc::c_char {
+        let mode: &'static [u8] = match *self {
+            LoadMode::Text => b"t\0",
+            LoadMode::Binary => b"b\0",
+            LoadMode::Both => b"bt\0",
+        };
+        mode.as_ptr() as *const libc::c_char
+    }
+}
 
 /// A result which may return a Lua load-time error.
 pub type LoadResult<T> = result::Result<T, LoadError>;
@@ -120,7 +280,18 @@ pub enum LoadError {
     /// A UTF-8 conversion error occurred.
     Utf8(FromUtf8Error),
     /// A syntax error occurred.
-    Syntax(String),
+    Syntax(SyntaxError),
+}
+
+impl LoadError {
+    /// Returns `true` if this is a syntax error caused by the chunk ending prematurely, meaning
+    /// that a REPL should ask for more input instead of reporting the error.
+    pub fn is_incomplete(&self) -> bool {
+        match *self {
+            LoadError::Syntax(ref err) => err.is_incomplete(),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for LoadError {
@@ -128,7 +299,7 @@ impl fmt::Display for LoadError {
         match *self {
             LoadError::Io(ref err) => err.fmt(f),
             LoadError::Utf8(ref err) => err.fmt(f),
-            LoadError::Syntax(ref msg) => write!(f, "{}", msg),
+            LoadError::Syntax(ref err) => err.fmt(f),
         }
     }
 }
@@ -163,12 +334,166 @@ impl From<FromUtf8Error> for LoadError {
     }
 }
 
+/// Describes a Lua syntax error.
+///
+/// Lua reports syntax errors as a single message such as
+/// `[string "x"]:3: '=' expected near 'foo'`, which is split into its components here. Errors
+/// which are not tied to a location, such as loading a binary chunk in `LoadMode::Text`, only have
+/// a `message`.
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    /// The chunk name as printed by Lua, e.g. `[string "x"]` or `script.lua`.
+    pub chunk: Option<String>,
+    /// The line where the error was detected.
+    pub line: Option<u32>,
+    /// The error message, without the location and offending token.
+    pub message: String,
+    /// The token near which the error was detected, without quotes, e.g. `foo` or `<eof>`.
+    pub near: Option<String>,
+    incomplete: bool,
+    raw: String,
+}
+
+impl SyntaxError {
+    /// Parses a syntax error message generated by Lua.
+    pub fn parse(raw: &str) -> SyntaxError {
+        let (chunk, line, rest) = match split_location(raw) {
+            Some((chunk, line, rest)) => (Some(chunk.to_string()), Some(line), rest),
+            None => (None, None, raw),
+        };
+        let (message, near, incomplete) = match rest.rfind(" near ") {
+            Some(pos) => {
+                let token = &rest[pos + 6..];
+                // Lua quotes every token except for the end of the stream
+                let (token, incomplete) = if token.len() >= 2 && token.starts_with('\'') &&
+                                             token.ends_with('\'') {
+                    (&token[1..token.len() - 1], false)
+                } else {
+                    (token, token == "<eof>")
+                };
+                (&rest[..pos], Some(token.to_string()), incomplete)
+            }
+            None => (rest, None, false),
+        };
+        SyntaxError {
+            chunk: chunk,
+            line: line,
+            message: message.to_string(),
+            near: near,
+            incomplete: incomplete,
+            raw: raw.to_string(),
+        }
+    }
+
+    /// Returns `true` if the error was detected at the end of the chunk (`near <eof>`), which
+    /// means that the chunk is incomplete rather than malformed.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl error::Error for SyntaxError {
+    fn description(&self) -> &str {
+        "Lua syntax error"
+    }
+}
+
+// Splits "chunk:line: rest" into its components. The chunk name may contain colons itself, so
+// look for the first ":<digits>: " after the closing bracket of a `[string "..."]` chunk name.
+fn split_location(raw: &str) -> Option<(&str, u32, &str)> {
+    let mut start = if raw.starts_with("[string \"") {
+        raw.find("\"]").map(|pos| pos + 2).unwrap_or(0)
+    } else {
+        0
+    };
+    while let Some(offset) = raw[start..].find(':') {
+        let colon = start + offset;
+        let digits = raw[colon + 1..].bytes().take_while(|b| *b >= b'0' && *b <= b'9').count();
+        let digits_end = colon + 1 + digits;
+        if digits > 0 && raw[digits_end..].starts_with(": ") {
+            if let Ok(line) = raw[colon + 1..digits_end].parse() {
+                return Some((&raw[..colon], line, &raw[digits_end + 2..]));
+            }
+        }
+        start = colon + 1;
+    }
+    None
+}
+
+/// A result which may return either a Lua load-time or run-time error.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Describes an error which occurred while loading or running a Lua chunk.
+#[derive(Debug)]
+pub enum Error {
+    /// The chunk could not be loaded.
+    Load(LoadError),
+    /// The chunk raised an error while running.
+    Run(RunError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Load(ref err) => err.fmt(f),
+            Error::Run(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Load(ref err) => err.description(),
+            Error::Run(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Load(ref err) => Some(err),
+            Error::Run(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(err: LoadError) -> Error {
+        Error::Load(err)
+    }
+}
+
+impl From<RunError> for Error {
+    fn from(err: RunError) -> Error {
+        Error::Run(err)
+    }
+}
+
 /// A result which may return a Lua runtime error.
 pub type RunResult<T> = result::Result<T, RunError>;
 
+/// Describes the cause of a Lua run-time error.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RunErrorKind {
+    /// An error raised by a script or native function, or a failed type conversion.
+    Runtime,
+    /// The script was cancelled through an `InterruptHandle`.
+    Interrupted,
+    /// A userdata finalizer (`__gc` metamethod) failed, e.g. because a `Drop` implementation
+    /// panicked.
+    Finalizer,
+}
+
 /// Describes a Lua run-time error.
 #[derive(Debug)]
 pub struct RunError {
+    pub kind: RunErrorKind,
     pub message: String,
     pub backtrace: Vec<String>,
 }
@@ -177,11 +502,31 @@ impl RunError {
     /// Generate an error with the given message and backtrace.
     pub fn new(message: String, backtrace: Vec<String>) -> RunError {
         RunError {
+            kind: RunErrorKind::Runtime,
             message: message,
             backtrace: backtrace,
         }
     }
 
+    /// Generate the error raised when a script is cancelled through an `InterruptHandle`.
+    pub fn interrupted(backtrace: Vec<String>) -> RunError {
+        RunError {
+            kind: RunErrorKind::Interrupted,
+            message: "interrupted".to_string(),
+            backtrace: backtrace,
+        }
+    }
+
+    /// Generate the error reported when a userdata finalizer fails. Finalizers do not run in the
+    /// context of a function, so there is no backtrace.
+    pub fn finalizer(message: String) -> RunError {
+        RunError {
+            kind: RunErrorKind::Finalizer,
+            message: message,
+            backtrace: Vec::new(),
+        }
+    }
+
     /// Generate a type conversion error message (Lua -> Rust)
     pub fn conversion_from_lua(src_type: Option<LuaType>,
                                dst_type: &'static str,
@@ -196,6 +541,7 @@ impl RunError {
             None => format!("invalid index"),
         };
         RunError {
+            kind: RunErrorKind::Runtime,
             message: message,
             backtrace: backtrace,
         }
@@ -210,6 +556,7 @@ impl RunError {
                               src_type,
                               dst_type);
         RunError {
+            kind: RunErrorKind::Runtime,
             message: message,
             backtrace: backtrace,
         }
@@ -299,3 +646,939 @@ fn test_error() {
     let result = state.call(0, LuaCallResults::Num(0));
     assert!(result.is_err() && result.err().unwrap().message == "Test error~");
 }
+
+#[test]
+fn test_interrupt() {
+    use std::thread;
+    use std::time::Duration;
+
+    let mut state = State::new();
+    let handle = state.interrupt_handle();
+    let watchdog = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    state.load_string("while true do end", "loop", LoadMode::Text).unwrap();
+    let result = state.call(0, LuaCallResults::Num(0));
+    watchdog.join().unwrap();
+    assert!(result.is_err() && result.err().unwrap().message == "interrupted");
+    assert!(state.get_top() == 0);
+}
+
+#[test]
+fn test_state_builder() {
+    let mut state = StateBuilder::safe().build();
+    assert!(state.get_global("io") == LuaType::Nil);
+    assert!(state.get_global("dofile") == LuaType::Nil);
+    assert!(state.get_global("rawset") == LuaType::Nil);
+    assert!(state.get_global("os") == LuaType::Table);
+    assert!(state.get_field(LuaIndex::Stack(-1), "execute") == LuaType::Nil);
+    assert!(state.get_field(LuaIndex::Stack(-2), "time") == LuaType::Function);
+    state.set_top(0);
+
+    // Binary chunks are rejected even if the script asks for them
+    state.load_string("return load(string.dump(function() return 1 end), 'f', 'b')",
+                      "dump",
+                      LoadMode::Text)
+        .unwrap();
+    state.call(0, LuaCallResults::Num(2)).unwrap();
+    assert!(state.is_nil(LuaIndex::Stack(-2)));
+    assert!(state.is_string(LuaIndex::Stack(-1)));
+}
+
+#[test]
+fn test_env() {
+    let mut state = State::new();
+    state.open_libs();
+    state.get_global("_G");
+    state.push_read_only(LuaIndex::Stack(-1));
+    state.remove(RelIndex::new(-2).unwrap());
+    state.new_env(LuaIndex::Stack(-1));
+    state.new_env(LuaIndex::Stack(-2));
+
+    // Globals are written to the chunk's own environment, and reads fall back to the base
+    state.load_string_with_env("x = 1; return type(print)",
+                              "a",
+                              LuaIndex::Stack(-2),
+                              LoadMode::Text)
+        .unwrap();
+    state.call(0, LuaCallResults::Num(1)).unwrap();
+    let ty: String = state.at(LuaIndex::Stack(-1)).unwrap();
+    state.pop(1);
+    assert!(ty == "function");
+    state.load_string_with_env("return x", "b", LuaIndex::Stack(-1), LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(1)).unwrap();
+    assert!(state.is_nil(LuaIndex::Stack(-1)));
+    state.pop(1);
+
+    // The shared base cannot be modified
+    state.load_string_with_env("print = nil", "c", LuaIndex::Stack(-3), LoadMode::Text).unwrap();
+    assert!(state.call(0, LuaCallResults::Num(0)).is_err());
+    state.pop(3);
+    assert!(state.get_top() == 0);
+
+    // Views support `pairs()` and `#` without exposing the original table
+    state.exec("t = {1, 2, 3}").unwrap();
+    state.get_global("t");
+    state.push_read_only(LuaIndex::Stack(-1));
+    state.set_global("ro");
+    state.pop(1);
+    let (sum, len, hidden): (i32, i32, bool) =
+        state.eval("local sum = 0
+                    for _, v in pairs(ro) do sum = sum + v end
+                    return sum, #ro, select(2, pairs(ro)) == ro")
+            .unwrap();
+    assert!(sum == 6 && len == 3 && hidden);
+}
+
+#[test]
+fn test_dump() {
+    let mut state = State::new();
+    state.load_string("return 6 * 7", "answer", LoadMode::Text).unwrap();
+    let bytecode = state.dump(true);
+    state.pop(1);
+    assert!(!bytecode.is_empty());
+
+    // Bytecode is rejected in text mode
+    assert!(state.load_bytes(&bytecode, "answer", LoadMode::Text).is_err());
+    assert!(state.get_top() == 0);
+
+    state.load_bytes(&bytecode, "answer", LoadMode::Binary).unwrap();
+    state.call(0, LuaCallResults::Num(1)).unwrap();
+    let answer: i32 = state.at(LuaIndex::Stack(-1)).unwrap();
+    assert!(answer == 42);
+}
+
+#[test]
+fn test_load_stream_error() {
+    use std::io::{self, Read};
+
+    struct FailingReader {
+        sent: bool,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.sent {
+                Err(io::Error::new(io::ErrorKind::Other, "disk on fire"))
+            } else {
+                self.sent = true;
+                buf[0] = b'x';
+                Ok(1)
+            }
+        }
+    }
+
+    let mut state = State::new();
+    match state.load_stream(FailingReader { sent: false }, "fail", LoadMode::Text) {
+        Err(LoadError::Io(ref err)) => assert!(err.to_string() == "disk on fire"),
+        _ => panic!("expected an IO error"),
+    }
+    assert!(state.get_top() == 0);
+    assert!(state.load_stream(&b"return 1"[..], "a\0b", LoadMode::Text).is_err());
+    assert!(state.load_string("return 1", "a\0b", LoadMode::Text).is_err());
+    assert!(state.get_top() == 0);
+    assert!(state.eval::<String>("return 'a\0b'").unwrap() == "a\0b");
+
+    // Large sources span several reads
+    let source = format!("return {}1", "0 + ".repeat(5000));
+    state.load_stream(source.as_bytes(), "big", LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(1)).unwrap();
+    let result: i32 = state.at(LuaIndex::Stack(-1)).unwrap();
+    assert!(result == 1);
+}
+
+#[test]
+fn test_syntax_error() {
+    let err = SyntaxError::parse("[string \"a:1: b\"]:3: '=' expected near 'foo'");
+    assert!(err.chunk.as_ref().unwrap() == "[string \"a:1: b\"]");
+    assert!(err.line == Some(3));
+    assert!(err.message == "'=' expected");
+    assert!(err.near.as_ref().unwrap() == "foo");
+    assert!(!err.is_incomplete());
+
+    let mut state = State::new();
+    match state.load_string("if x then", "repl", LoadMode::Text) {
+        Err(err) => assert!(err.is_incomplete()),
+        Ok(_) => panic!("expected a syntax error"),
+    }
+    assert!(state.get_top() == 0);
+}
+
+#[test]
+fn test_do_file() {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("lowlua_test_do_file.lua");
+    {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"#!/usr/bin/env lua\nreturn 1 + 1, 'two'\n").unwrap();
+    }
+    let mut state = State::new();
+    let (num, name): (i32, String) = state.do_file(&path).unwrap();
+    assert!(num == 2 && name == "two");
+
+    let num: i32 = state.eval("return 40 + 2").unwrap();
+    assert!(num == 42);
+    assert!(state.exec("error('oops')").is_err());
+    match state.exec("return +") {
+        Err(Error::Load(_)) => (),
+        _ => panic!("expected a load error"),
+    }
+    assert!(state.get_top() == 0);
+}
+
+#[test]
+fn test_require() {
+    fn open_native(state: &mut State) -> RunResult<u32> {
+        state.new_table();
+        state.push(7);
+        state.set_field(LuaIndex::Stack(-2), "answer");
+        Ok(1)
+    }
+
+    let mut state = State::new();
+    state.open_libs();
+    state.add_module_source("ai.pathing", "return { find = function() return 'found' end }")
+        .unwrap();
+    state.add_native_module("native", open_native).unwrap();
+    let (found, answer, cached): (String, i32, bool) =
+        state.eval("local p = require 'ai.pathing'
+                    return p.find(), require('native').answer, p == require 'ai.pathing'")
+            .unwrap();
+    assert!(found == "found" && answer == 7 && cached);
+    assert!(state.exec("require 'missing'").is_err());
+    assert!(state.get_top() == 0);
+}
+
+#[test]
+fn test_module_builder() {
+    fn greet(state: &mut State) -> RunResult<u32> {
+        let greeting: String = try!(state.at(LuaIndex::Upvalue(1)));
+        let name: String = try!(state.at(LuaIndex::Stack(1)));
+        state.push(format!("{}, {}!", greeting, name));
+        Ok(1)
+    }
+
+    let mut state = State::new();
+    state.push("Hello");
+    ModuleBuilder::new()
+        .function("greet", greet)
+        .constant("answer", 42)
+        .submodule("nested", ModuleBuilder::new().function("greet", greet))
+        .push_with_upvalues(&mut state, 1);
+    state.set_global("greeter");
+    assert!(state.get_top() == 0);
+
+    let (greeting, answer, nested): (String, i32, String) =
+        state.eval("return greeter.greet('Lua'), greeter.answer, greeter.nested.greet('Rust')")
+            .unwrap();
+    assert!(greeting == "Hello, Lua!" && answer == 42 && nested == "Hello, Rust!");
+}
+
+#[test]
+fn test_lua_module() {
+    fn open(state: &mut State) -> RunResult<u32> {
+        let name: String = try!(state.at(LuaIndex::Stack(1)));
+        ModuleBuilder::new().constant("name", name).push(state);
+        Ok(1)
+    }
+
+    lua_module!(luaopen_lowlua_test, open);
+
+    // Simulate `require` from an interpreter which doesn't know about lowlua
+    unsafe {
+        let lua = ffi::luaL_newstate();
+        ffi::lua_pushstring(lua, b"lowlua_test\0".as_ptr() as *const libc::c_char);
+        assert!(luaopen_lowlua_test(lua) == 1);
+        assert!(ffi::lua_istable(lua, -1));
+        ffi::lua_getfield(lua, -1, b"name\0".as_ptr() as *const libc::c_char);
+        let name = std::ffi::CStr::from_ptr(ffi::lua_tostring(lua, -1));
+        assert!(name.to_str().unwrap() == "lowlua_test");
+        ffi::lua_close(lua);
+    }
+}
+
+#[test]
+fn test_from_raw() {
+    unsafe {
+        let lua = ffi::luaL_newstate();
+        {
+            let mut state = State::from_raw(lua);
+            assert!(state.as_raw_ptr() == lua);
+            state.push(42);
+            state.set_global("answer");
+            let answer: i32 = state.eval("return answer").unwrap();
+            assert!(answer == 42);
+        }
+        // The state must still be usable after the wrapper is dropped
+        ffi::lua_getglobal(lua, b"answer\0".as_ptr() as *const libc::c_char);
+        assert!(ffi::lua_tointegerx(lua, -1, std::ptr::null_mut()) == 42);
+        ffi::lua_close(lua);
+    }
+}
+
+#[test]
+fn test_compare() {
+    use std::cmp::Ordering;
+
+    let mut state = State::new();
+    state.push(1);
+    state.push(2);
+    assert!(state.compare(LuaIndex::Stack(1), LuaIndex::Stack(2), LuaCompare::Lt).unwrap());
+    assert!(state.compare(LuaIndex::Stack(1), LuaIndex::Stack(2), LuaCompare::Le).unwrap());
+    assert!(!state.compare(LuaIndex::Stack(1), LuaIndex::Stack(2), LuaCompare::Eq).unwrap());
+    assert!(state.partial_cmp(LuaIndex::Stack(2), LuaIndex::Stack(1)) == Some(Ordering::Greater));
+    state.push(0.0 / 0.0);
+    assert!(state.partial_cmp(LuaIndex::Stack(1), LuaIndex::Stack(3)) == None);
+    state.set_top(0);
+
+    // Comparing a table with a number raises an error instead of aborting
+    state.new_table();
+    state.push(1);
+    assert!(state.compare(LuaIndex::Stack(1), LuaIndex::Stack(2), LuaCompare::Lt).is_err());
+    assert!(state.partial_cmp(LuaIndex::Stack(1), LuaIndex::Stack(2)) == None);
+    assert!(state.get_top() == 2);
+    state.set_top(0);
+
+    let sum: i32 = state.arith_values(LuaOperator::Add, 2, 3).unwrap();
+    assert!(sum == 5);
+    let neg: f64 = state.arith_values(LuaOperator::Unm, 1.5, 0).unwrap();
+    assert!(neg == -1.5);
+    assert!(state.get_top() == 0);
+}
+
+#[test]
+fn test_protect() {
+    let mut state = State::new();
+    state.push(1);
+    state.push("x");
+    let result = state.protect(2, LuaCallResults::Num(1), |state| {
+        state.arith(LuaOperator::Add);
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert!(state.get_top() == 0);
+
+    state.push(20);
+    let val = state.protect(1, LuaCallResults::Num(1), |state| {
+        let val: i32 = try!(state.at(LuaIndex::Stack(1)));
+        state.push(val + 1);
+        Ok(val * 2)
+    });
+    assert!(val.unwrap() == 40);
+    let pushed: i32 = state.at(LuaIndex::Stack(-1)).unwrap();
+    assert!(pushed == 21 && state.get_top() == 1);
+}
+
+#[test]
+fn test_gc_panic() {
+    use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+    static REPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+    struct Bomb;
+
+    impl Drop for Bomb {
+        fn drop(&mut self) {
+            panic!("boom");
+        }
+    }
+
+    fn report(err: &RunError) {
+        assert!(err.kind == RunErrorKind::Finalizer && err.message.contains("boom"));
+        REPORTED.store(true, Ordering::SeqCst);
+    }
+
+    // Panics during a collection are returned as errors
+    let mut state = State::new();
+    state.push_userdata(Bomb);
+    state.pop(1);
+    let err = state.gc_collect().err().unwrap();
+    assert!(err.kind == RunErrorKind::Finalizer && err.message.contains("boom"));
+    assert!(state.get_top() == 0);
+
+    // Panics while closing the state are passed to the handler
+    state.set_finalizer_panic_handler(report);
+    state.push_userdata(Bomb);
+    state.set_global("bomb");
+    drop(state);
+    assert!(REPORTED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_stack_guard() {
+    let mut state = State::new();
+    state.push(1);
+    {
+        let mut guard = state.stack_guard();
+        guard.push(2);
+        guard.push(3);
+        assert!(guard.saved_top() == 1 && guard.get_top() == 3);
+    }
+    assert!(state.get_top() == 1);
+    {
+        let mut check = state.stack_check();
+        check.push(2);
+        check.pop(1);
+    }
+
+    // Native functions claiming more results than they pushed are caught in debug builds
+    fn bad_function(state: &mut State) -> RunResult<u32> {
+        state.push(1);
+        Ok(3)
+    }
+    if cfg!(debug_assertions) {
+        state.push_function(bad_function);
+        assert!(state.call(0, LuaCallResults::Num(0)).is_err());
+        assert!(state.get_top() == 1);
+    }
+
+    // Values left below the results are only an error in strict mode
+    fn leaky_function(state: &mut State) -> RunResult<u32> {
+        state.push(1);
+        state.push(2);
+        Ok(1)
+    }
+    state.push_function(leaky_function);
+    assert!(state.call(0, LuaCallResults::Num(0)).is_ok());
+    if cfg!(debug_assertions) {
+        state.set_strict_native_results(true);
+        state.push_function(leaky_function);
+        assert!(state.call(0, LuaCallResults::Num(0)).is_err());
+        state.set_strict_native_results(false);
+    }
+    assert!(state.get_top() == 1);
+}
+
+#[test]
+fn test_stack_slots() {
+    let mut state = State::new();
+    assert!(AbsIndex::new(0).is_none());
+    assert!(RelIndex::new(ffi::LUA_REGISTRYINDEX).is_none());
+    assert!(state.try_remove(RelIndex::new(-1).unwrap()).is_err());
+
+    state.push(1);
+    state.push(2);
+    state.push(3);
+    assert!(state.abs_slot(RelIndex::new(-1).unwrap()) == AbsIndex::new(3));
+    state.try_rotate(AbsIndex::new(1).unwrap(), 1).unwrap();
+    let first: i32 = state.at(LuaIndex::Stack(1)).unwrap();
+    assert!(first == 3);
+    assert!(state.try_rotate(AbsIndex::new(2).unwrap(), 3).is_err());
+    assert!(state.try_copy(AbsIndex::new(1).unwrap(), AbsIndex::new(4).unwrap()).is_err());
+    state.try_replace(AbsIndex::new(1).unwrap()).unwrap();
+    assert!(state.get_top() == 2);
+    assert!(state.try_insert(AbsIndex::new(4).unwrap()).is_err());
+}
+
+#[test]
+fn test_debug_info() {
+    fn inspect(state: &mut State) -> RunResult<u32> {
+        let found = state.with_stack_level(1, |state, ar| {
+            let info = state.get_info(ar, "Slnu");
+            assert!(info.short_src == "[string \"test\"]" && info.current_line == Some(3));
+            assert!(info.name.as_ref().map(|s| &s[..]) == Some("f") && info.nparams == 2);
+            assert!(state.get_local(ar, 1) == Some("a".to_string()));
+            assert!(state.at::<i32>(LuaIndex::Stack(-1)).unwrap() == 1);
+            state.pop(1);
+            state.push(10);
+            assert!(state.set_local(ar, 3) == Some("c".to_string()));
+            assert!(state.get_local(ar, 4).is_none());
+        });
+        assert!(found.is_some());
+        assert!(state.stack_depth() == 3 && state.with_stack_level(3, |_, _| ()).is_none());
+        Ok(0)
+    }
+    let mut state = State::new();
+    state.push_function(inspect);
+    state.set_global("inspect");
+    state.load_string("function f(a, b)
+                           local c = a + b
+                           inspect()
+                           return c
+                       end
+                       result = f(1, 2)",
+                      "test",
+                      LoadMode::Text)
+        .unwrap();
+    state.call(0, LuaCallResults::Num(0)).unwrap();
+    state.get_global("result");
+    assert!(state.at::<i32>(LuaIndex::Stack(-1)).unwrap() == 10);
+
+    state.get_global("f");
+    let info = state.get_function_info(LuaIndex::Stack(-1), "SuL");
+    assert!(info.what == "Lua" && info.line_defined == 1 && info.last_line_defined == 5);
+    assert!(info.active_lines == vec![2, 3, 4, 5]);
+    assert!(state.parameter_name(LuaIndex::Stack(-1), 2) == Some("b".to_string()));
+    assert!(state.get_top() == 2);
+}
+
+#[test]
+fn test_upvalues() {
+    let mut state = State::new();
+    state.exec("local count, step = 0, 1
+                function counter() count = count + step; return count end
+                function other() local x = 0; return function() x = x + 1; return x end end")
+        .unwrap();
+    state.get_global("counter");
+    assert!(state.find_upvalue(LuaIndex::Stack(1), "step") == Some(2));
+    assert!(state.get_upvalue(LuaIndex::Stack(1), 1) == Some("count".to_string()));
+    assert!(state.at::<i32>(LuaIndex::Stack(-1)).unwrap() == 0);
+    state.pop(1);
+    state.push(10);
+    assert!(state.set_upvalue_by_name(LuaIndex::Stack(1), "step"));
+    assert!(state.eval::<i32>("return counter()").unwrap() == 10);
+
+    // Join `count` with the upvalue of a fresh closure
+    state.exec("f = other()").unwrap();
+    state.get_global("f");
+    assert!(state.upvalue_id(LuaIndex::Stack(1), 1) != state.upvalue_id(LuaIndex::Stack(2), 1));
+    state.upvalue_join(LuaIndex::Stack(1), 1, LuaIndex::Stack(2), 1).unwrap();
+    assert!(state.upvalue_id(LuaIndex::Stack(1), 1) == state.upvalue_id(LuaIndex::Stack(2), 1));
+    assert!(state.eval::<i32>("return f()").unwrap() == 1);
+    assert!(state.eval::<i32>("return counter()").unwrap() == 11);
+
+    // The upvalue used by lowlua is hidden
+    fn native(_: &mut State) -> RunResult<u32> {
+        Ok(0)
+    }
+    state.push("data");
+    state.push_closure(native, 1);
+    assert!(state.get_function_info(LuaIndex::Stack(-1), "u").nups == 1);
+    assert!(state.get_upvalue(LuaIndex::Stack(-1), 1) == Some("".to_string()));
+    assert!(state.at::<String>(LuaIndex::Stack(-1)).unwrap() == "data");
+    assert!(state.get_upvalue(LuaIndex::Stack(-2), 2).is_none());
+    assert!(state.upvalue_join(LuaIndex::Stack(-2), 1, LuaIndex::Stack(1), 1).is_err());
+}
+
+#[test]
+fn test_hooks() {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    let mut state = State::new();
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let hook_lines = lines.clone();
+    let mask = HookMask { line: true, ..HookMask::default() };
+    let id = state.add_hook(mask, move |_, event| {
+        if let HookEvent::Line(line) = event {
+            hook_lines.borrow_mut().push(line);
+        }
+        Ok(())
+    });
+    // The interrupt check shares the dispatcher with other hooks
+    let handle = state.interrupt_handle();
+    state.exec("local a = 1\nlocal b = 2").unwrap();
+    assert!(*lines.borrow() == vec![1, 2]);
+    handle.interrupt();
+    assert!(state.exec("while true do end").is_err());
+    assert!(state.remove_hook(id) && !state.remove_hook(id));
+    lines.borrow_mut().clear();
+    state.exec("local a = 1").unwrap();
+    assert!(lines.borrow().is_empty());
+
+    // Errors returned by hooks are raised in the script
+    let mask = HookMask { call: true, ..HookMask::default() };
+    state.add_hook(mask,
+                   |state, _| Err(RunError::new("hooked".to_string(), state.backtrace())));
+    assert!(state.exec("local function f() end f()").is_err());
+}
+
+#[test]
+fn test_hooks_coroutines() {
+    use std::rc::Rc;
+    use std::cell::{Cell, RefCell};
+
+    const COROUTINE: &'static str = "return coroutine.wrap(function()
+                                         while true do
+                                             coroutine.yield()
+                                         end
+                                     end)";
+    let mut state = State::new();
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let line_hook = |state: &mut State, lines: &Rc<RefCell<Vec<u32>>>| {
+        let hook_lines = lines.clone();
+        let mask = HookMask { line: true, ..HookMask::default() };
+        state.add_hook(mask, move |_, event| {
+            if let HookEvent::Line(line) = event {
+                hook_lines.borrow_mut().push(line);
+            }
+            Ok(())
+        })
+    };
+
+    // A coroutine created while no hook is installed never calls hooks
+    state.load_string(COROUTINE, "=co", LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(1)).unwrap();
+    state.set_global("unhooked");
+    let id = line_hook(&mut state, &lines);
+    state.exec("unhooked() unhooked()").unwrap();
+    assert!(!lines.borrow().contains(&3));
+    assert!(state.remove_hook(id));
+
+    // A coroutine created while a hook is installed picks up later changes
+    let count = state.add_hook(HookMask { count: Some(1), ..HookMask::default() }, |_, _| Ok(()));
+    state.load_string(COROUTINE, "=co", LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(1)).unwrap();
+    state.set_global("hooked");
+    let id = line_hook(&mut state, &lines);
+    state.exec("hooked() hooked()").unwrap();
+    assert!(lines.borrow().contains(&3));
+    assert!(state.remove_hook(id) && state.remove_hook(count));
+    lines.borrow_mut().clear();
+    state.exec("hooked() hooked()").unwrap();
+    assert!(lines.borrow().is_empty());
+
+    // Count hooks are called at their exact rates
+    let ticks = Rc::new([Cell::new(0i64), Cell::new(0i64)]);
+    for &(i, n) in &[(0, 2), (1, 3)] {
+        let hook_ticks = ticks.clone();
+        state.add_hook(HookMask { count: Some(n), ..HookMask::default() }, move |_, _| {
+            hook_ticks[i].set(hook_ticks[i].get() + 1);
+            Ok(())
+        });
+    }
+    state.exec("local s = 0 for i = 1, 10000 do s = s + i end").unwrap();
+    assert!(ticks[0].get() > 1000 && (ticks[0].get() * 2 - ticks[1].get() * 3).abs() <= 6);
+}
+
+#[cfg(feature = "dap")]
+#[test]
+fn test_dap() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use serde_json::Value;
+
+    const SCRIPT: &'static str = "local function add(a, b)
+                                      local sum = a + b
+                                      return sum
+                                  end
+                                  local x = add(1, 2)
+                                  result = x * 2";
+
+    fn send(stream: &mut TcpStream, seq: u64, command: &str, arguments: Value) {
+        let body = json!({
+            "seq": seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        let body = body.to_string();
+        write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+
+    // Skips messages until a response to `name` or an event called `name` arrives
+    fn expect<R: BufRead>(reader: &mut R, name: &str) -> Value {
+        loop {
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                length = line["Content-Length:".len()..].trim().parse().unwrap();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let message: Value = serde_json::from_slice(&body).unwrap();
+            if message["command"] == name || message["event"] == name {
+                return message;
+            }
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        send(&mut stream, 1, "initialize", json!({ "adapterID": "lowlua" }));
+        assert!(expect(&mut reader, "initialize")["success"] == true);
+        expect(&mut reader, "initialized");
+        send(&mut stream,
+             2,
+             "setBreakpoints",
+             json!({ "source": { "path": "test.lua" }, "breakpoints": [{ "line": 3 }] }));
+        assert!(expect(&mut reader, "setBreakpoints")["body"]["breakpoints"][0]["verified"] ==
+                true);
+        send(&mut stream, 3, "configurationDone", json!({}));
+        expect(&mut reader, "configurationDone");
+
+        assert!(expect(&mut reader, "stopped")["body"]["reason"] == "breakpoint");
+        send(&mut stream, 4, "stackTrace", json!({ "threadId": 1 }));
+        let frames = expect(&mut reader, "stackTrace")["body"]["stackFrames"].clone();
+        assert!(frames[0]["name"] == "add" && frames[0]["line"] == 3);
+        assert!(frames[0]["source"]["path"] == "test.lua" && frames[1]["line"] == 5);
+        send(&mut stream, 5, "scopes", json!({ "frameId": 1 }));
+        let locals = expect(&mut reader, "scopes")["body"]["scopes"][0]["variablesReference"]
+            .clone();
+        send(&mut stream, 6, "variables", json!({ "variablesReference": locals }));
+        let variables = expect(&mut reader, "variables")["body"]["variables"].clone();
+        assert!(variables[0]["name"] == "a" && variables[0]["value"] == "1");
+        assert!(variables[2]["name"] == "sum" && variables[2]["value"] == "3");
+        send(&mut stream,
+             7,
+             "evaluate",
+             json!({ "expression": "sum * 10", "frameId": 1 }));
+        assert!(expect(&mut reader, "evaluate")["body"]["result"] == "30");
+
+        send(&mut stream, 8, "next", json!({ "threadId": 1 }));
+        assert!(expect(&mut reader, "stopped")["body"]["reason"] == "step");
+        send(&mut stream, 9, "stackTrace", json!({ "threadId": 1 }));
+        assert!(expect(&mut reader, "stackTrace")["body"]["stackFrames"][0]["line"] == 6);
+        send(&mut stream, 10, "continue", json!({ "threadId": 1 }));
+        expect(&mut reader, "terminated");
+    });
+
+    let mut state = State::new();
+    let session = dap::DebugSession::accept(&mut state, &listener).unwrap();
+    assert!(session.wait_for_configuration(&mut state));
+    state.load_string(SCRIPT, "@test.lua", LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(0)).unwrap();
+    session.close(&mut state);
+    client.join().unwrap();
+    state.get_global("result");
+    assert!(state.at::<i32>(LuaIndex::Stack(-1)).unwrap() == 6);
+}
+
+#[test]
+fn test_profiler() {
+    const SCRIPT: &'static str = "local function f(n)
+                                      local s = 0
+                                      for i = 1, n do s = s + i end
+                                      return s
+                                  end
+                                  for i = 1, 3 do f(10000) end";
+    let mut state = State::new();
+
+    let profiler = state.start_profiler(ProfileMode::Instrument);
+    state.load_string(SCRIPT, "@prof.lua", LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(0)).unwrap();
+    let profile = profiler.stop(&mut state);
+    let f = profile.function("prof.lua:1 f").unwrap();
+    assert!(f.calls == 3 && f.total_time >= f.self_time);
+    let main = profile.function("prof.lua:0 main chunk").unwrap();
+    assert!(main.total_time >= f.total_time);
+    assert!(profile.collapsed_stacks().contains("prof.lua:0 main chunk;prof.lua:1 f "));
+    assert!(profile.to_json().contains("{\"name\":\"prof.lua:1 f\",\"calls\":3,"));
+
+    let profiler = state.start_profiler(ProfileMode::Sample(100));
+    state.load_string(SCRIPT, "@prof.lua", LoadMode::Text).unwrap();
+    state.call(0, LuaCallResults::Num(0)).unwrap();
+    let profile = profiler.stop(&mut state);
+    assert!(profile.functions()[0].name == "prof.lua:1 f");
+    assert!(profile.functions()[0].samples > 0 && profile.functions()[0].calls == 0);
+
+    // Calls left by an error don't stay on the profiler's stack
+    let profiler = state.start_profiler(ProfileMode::Instrument);
+    state.load_string("local function g() error('g') end
+                       for i = 1, 3 do pcall(g) end
+                       local s = 0
+                       for i = 1, 100000 do s = s + i end",
+                      "@prof_err.lua",
+                      LoadMode::Text)
+        .unwrap();
+    state.call(0, LuaCallResults::Num(0)).unwrap();
+    let profile = profiler.stop(&mut state);
+    assert!(profile.function("prof_err.lua:1 g").unwrap().calls == 3);
+    assert!(!profile.collapsed_stacks().contains("pcall;[native] pcall"));
+    assert!(profile.collapsed_stacks().contains("prof_err.lua:0 main chunk "));
+
+    // Dropping a profiler removes its hook
+    drop(state.start_profiler(ProfileMode::Instrument));
+    state.exec("local a = 1").unwrap();
+    assert!(unsafe { ffi::lua_gethookmask(state.as_raw_ptr()) } == 0);
+}
+
+#[test]
+fn test_coverage() {
+    let mut state = State::new();
+    let collector = state.start_coverage();
+    state.load_string("local function used(x)
+                           if x > 0 then
+                               return 1
+                           else
+                               return 2
+                           end
+                       end
+                       used(1)",
+                      "@cov.lua",
+                      LoadMode::Text)
+        .unwrap();
+    state.call(0, LuaCallResults::Num(0)).unwrap();
+    let coverage = collector.stop(&mut state);
+    assert!(coverage.files() == vec!["cov.lua"]);
+    assert!(coverage.hits("cov.lua", 3) == Some(1) && coverage.hits("cov.lua", 8) == Some(1));
+    assert!(coverage.hits("cov.lua", 5) == Some(0));
+    let (hit, found) = coverage.summary();
+    assert!(hit > 0 && hit < found);
+
+    let lcov = coverage.to_lcov();
+    assert!(lcov.starts_with("TN:\nSF:cov.lua\n") && lcov.ends_with("end_of_record\n"));
+    assert!(lcov.contains("DA:3,1\n") && lcov.contains("DA:5,0\n"));
+    let cobertura = coverage.to_cobertura();
+    assert!(cobertura.contains("filename=\"cov.lua\""));
+    assert!(cobertura.contains("<line number=\"5\" hits=\"0\"/>"));
+
+    // Dropping a collector removes its hook
+    drop(state.start_coverage());
+    state.exec("local a = 1").unwrap();
+    assert!(unsafe { ffi::lua_gethookmask(state.as_raw_ptr()) } == 0);
+}
+
+#[test]
+fn test_memory_stats() {
+    let mut state = State::new();
+    let before = state.memory_stats().unwrap();
+    assert!(before.in_use > 0 && before.peak >= before.in_use);
+    state.exec("local t = {} for i = 1, 100 do t[i] = {} end").unwrap();
+    let after = state.memory_stats().unwrap();
+    assert!(after.tables.count >= before.tables.count + 101);
+    assert!(after.allocations > before.allocations && after.peak > before.peak);
+
+    state.set_userdata_accounting(true);
+    state.push_userdata([0u8; 1000]);
+    state.push_userdata([0u8; 1000]);
+    let userdata = state.userdata_memory();
+    assert!(userdata.len() == 1 && userdata[0].count == 2 && userdata[0].bytes >= 2000);
+    state.pop(2);
+    state.gc_collect().unwrap();
+    assert!(state.userdata_memory().is_empty());
+
+    // Values which were not counted are not subtracted
+    state.set_userdata_accounting(false);
+    state.push_userdata([0u8; 1000]);
+    state.set_userdata_accounting(true);
+    state.push_userdata([0u8; 1000]);
+    state.remove(RelIndex(-2));
+    state.gc_collect().unwrap();
+    assert!(state.userdata_memory()[0].count == 1);
+    state.pop(1);
+
+    let lua = state.as_raw_ptr();
+    assert!(unsafe { State::from_raw(lua) }.memory_stats().is_some());
+}
+
+#[test]
+fn test_gc_control() {
+    use std::time::Duration;
+
+    let mut state = State::new();
+    let previous = state.gc_configure(GcConfig {
+        pause: Some(150),
+        step_mul: Some(400),
+        ..GcConfig::default()
+    });
+    assert!(previous.pause == Some(200) && previous.running == Some(true));
+    let config = state.gc_config();
+    assert!(config.pause == Some(150) && config.step_mul == Some(400));
+    state.gc_configure(previous);
+    assert!(state.gc_config() == previous);
+
+    let running = state.with_gc_stopped(|state| {
+        state.exec("for i = 1, 1000 do local t = {} end").unwrap();
+        state.gc_is_running()
+    });
+    assert!(!running && state.gc_is_running());
+
+    state.gc_stop();
+    state.exec("for i = 1, 1000 do local t = {} end").unwrap();
+    let mut finished = false;
+    for _ in 0..10000 {
+        if state.gc_step(1).unwrap() {
+            finished = true;
+            break;
+        }
+    }
+    assert!(finished && !state.gc_is_running());
+    assert!(state.gc_step_for(Duration::from_secs(10)).unwrap());
+}
+
+#[test]
+fn test_weak_refs() {
+    let mut state = State::new();
+    state.new_table();
+    let strong = state.create_ref(LuaIndex::Stack(-1));
+    let weak = state.create_weak_ref(LuaIndex::Stack(-1));
+    state.pop(1);
+    state.gc_collect().unwrap();
+    let upgraded = weak.upgrade(&mut state).unwrap();
+    state.push(&upgraded);
+    state.push_ref(&strong);
+    assert!(state.raw_equal(LuaIndex::Stack(-1), LuaIndex::Stack(-2)));
+    state.pop(2);
+
+    // Released references are freed the next time the state resolves a reference
+    drop(strong);
+    drop(upgraded);
+    assert!(state.push_weak_ref(&weak));
+    state.pop(1);
+    state.gc_collect().unwrap();
+    assert!(weak.upgrade(&mut state).is_none());
+
+    state.push(42);
+    let number = state.create_weak_ref(LuaIndex::Stack(-1));
+    state.pop(1);
+    state.gc_collect().unwrap();
+    assert!(state.push_weak_ref(&number));
+    assert!(state.at::<i64>(LuaIndex::Stack(-1)).unwrap() == 42);
+    state.pop(1);
+
+    state.new_weak_table(WeakMode::Keys);
+    state.new_table();
+    state.push(true);
+    state.set_table(LuaIndex::Stack(-3));
+    state.gc_collect().unwrap();
+    state.push_nil();
+    assert!(!state.next(LuaIndex::Stack(-2)));
+}
+
+#[test]
+fn test_interning() {
+    use std::collections::HashSet;
+
+    let mut state = State::new();
+    let hello = state.intern("hello");
+    let copy = hello.clone();
+    assert!(hello.len() == 5 && hello == "hello" && hello == copy);
+    assert!(hello.as_str(&state) == Some("hello"));
+    assert!(state.interned_strings() == 1);
+
+    // Entries are removed after the last clone is dropped
+    drop(hello);
+    assert!(state.interned_strings() == 1);
+    drop(copy);
+    assert!(state.interned_strings() == 0);
+
+    for i in 0..100 {
+        let key: LuaString = state.eval(&format!("return 'key' .. {}", i)).unwrap();
+        assert!(key == &format!("key{}", i)[..]);
+    }
+    assert!(state.interned_strings() == 0);
+
+    let name = state.intern_permanent("name");
+    drop(name);
+    assert!(state.interned_strings() == 1);
+    let name = state.intern("name");
+    state.push(&name);
+    let pushed: String = state.at(LuaIndex::Stack(-1)).unwrap();
+    state.pop(1);
+    assert!(pushed == "name");
+
+    // Strings can still be compared and hashed after the state was closed
+    let a = state.intern("a");
+    let long = state.intern(&"b".repeat(100));
+    let mut set = HashSet::new();
+    set.insert(a.clone());
+    set.insert(long.clone());
+    drop(state);
+    assert!(set.contains(&a) && set.contains(&long) && a == "a" && a != long);
+    set.insert(a.clone());
+    assert!(set.len() == 2);
+}
@@ -0,0 +1,245 @@
+use libc::c_char;
+
+use ffi;
+use state::State;
+use ::{RunResult, LuaType, LuaIndex, LuaCallResults, AbsIndex};
+
+/// Enum of the standard Lua libraries.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StandardLib {
+    /// The basic library (`print`, `pairs`, `load`, ...), opened into the global table.
+    Base,
+    /// The package library (`require` and `package`).
+    Package,
+    /// The coroutine library (`coroutine`).
+    Coroutine,
+    /// The table library (`table`).
+    Table,
+    /// The input and output library (`io`).
+    Io,
+    /// The operating system library (`os`).
+    Os,
+    /// The string library (`string`).
+    String,
+    /// The mathematical library (`math`).
+    Math,
+    /// The UTF-8 library (`utf8`).
+    Utf8,
+    /// The debug library (`debug`).
+    Debug,
+}
+
+impl StandardLib {
+    /// The name the library is registered under in `package.loaded` and the global table.
+    fn name(&self) -> &'static [u8] {
+        match *self {
+            StandardLib::Base => b"_G\0",
+            StandardLib::Package => b"package\0",
+            StandardLib::Coroutine => b"coroutine\0",
+            StandardLib::Table => b"table\0",
+            StandardLib::Io => b"io\0",
+            StandardLib::Os => b"os\0",
+            StandardLib::String => b"string\0",
+            StandardLib::Math => b"math\0",
+            StandardLib::Utf8 => b"utf8\0",
+            StandardLib::Debug => b"debug\0",
+        }
+    }
+
+    fn open_fn(&self) -> ffi::lua_CFunction {
+        match *self {
+            StandardLib::Base => ffi::luaopen_base,
+            StandardLib::Package => ffi::luaopen_package,
+            StandardLib::Coroutine => ffi::luaopen_coroutine,
+            StandardLib::Table => ffi::luaopen_table,
+            StandardLib::Io => ffi::luaopen_io,
+            StandardLib::Os => ffi::luaopen_os,
+            StandardLib::String => ffi::luaopen_string,
+            StandardLib::Math => ffi::luaopen_math,
+            StandardLib::Utf8 => ffi::luaopen_utf8,
+            StandardLib::Debug => ffi::luaopen_debug,
+        }
+    }
+}
+
+/// Functions removed by the `sandboxed()` preset.
+const SANDBOX_REMOVED: &'static [&'static str] = &["dofile",
+                                                   "loadfile",
+                                                   "collectgarbage",
+                                                   "rawset"];
+
+/// Functions of the `os` library removed by the `safe()` preset.
+const OS_REMOVED: &'static [&'static str] = &["os.execute",
+                                              "os.exit",
+                                              "os.remove",
+                                              "os.rename",
+                                              "os.tmpname",
+                                              "os.getenv",
+                                              "os.setlocale"];
+
+/// Builds a `State` with a selected set of standard libraries.
+///
+/// Unlike `State::open_libs()`, which opens every standard library, the builder opens each library
+/// individually and can strip functions which should not be available to untrusted code.
+///
+/// ```ignore
+/// let mut state = StateBuilder::sandboxed().open(StandardLib::Os).remove("os.exit").build();
+/// ```
+pub struct StateBuilder {
+    libs: Vec<StandardLib>,
+    removed: Vec<String>,
+    text_only_load: bool,
+}
+
+impl StateBuilder {
+    /// Creates a builder which opens no libraries at all.
+    pub fn new() -> StateBuilder {
+        StateBuilder {
+            libs: Vec::new(),
+            removed: Vec::new(),
+            text_only_load: false,
+        }
+    }
+
+    /// Creates a builder suitable for running untrusted code which only needs to compute.
+    ///
+    /// The `base`, `coroutine`, `table`, `string`, `math` and `utf8` libraries are opened,
+    /// `dofile`, `loadfile`, `collectgarbage` and `rawset` are removed, and `load` only accepts
+    /// text chunks.
+    pub fn sandboxed() -> StateBuilder {
+        let mut builder = StateBuilder::new()
+            .open(StandardLib::Base)
+            .open(StandardLib::Coroutine)
+            .open(StandardLib::Table)
+            .open(StandardLib::String)
+            .open(StandardLib::Math)
+            .open(StandardLib::Utf8)
+            .text_only_load(true);
+        for path in SANDBOX_REMOVED {
+            builder = builder.remove(path);
+        }
+        builder
+    }
+
+    /// Like `sandboxed()`, but additionally opens the `os` library with every function that
+    /// touches the file system, environment or process removed. Only `os.clock`, `os.date`,
+    /// `os.difftime` and `os.time` remain.
+    pub fn safe() -> StateBuilder {
+        let mut builder = StateBuilder::sandboxed().open(StandardLib::Os);
+        for path in OS_REMOVED {
+            builder = builder.remove(path);
+        }
+        builder
+    }
+
+    /// Opens the given library when the state is built. Libraries are opened in the order they
+    /// are added.
+    pub fn open(mut self, lib: StandardLib) -> StateBuilder {
+        if !self.libs.contains(&lib) {
+            self.libs.push(lib);
+        }
+        self
+    }
+
+    /// Opens every standard library when the state is built.
+    pub fn open_all(self) -> StateBuilder {
+        self.open(StandardLib::Base)
+            .open(StandardLib::Package)
+            .open(StandardLib::Coroutine)
+            .open(StandardLib::Table)
+            .open(StandardLib::Io)
+            .open(StandardLib::Os)
+            .open(StandardLib::String)
+            .open(StandardLib::Math)
+            .open(StandardLib::Utf8)
+            .open(StandardLib::Debug)
+    }
+
+    /// Removes a global or a field of a global table once the libraries are opened. `path` is a
+    /// dot-separated path such as `"dofile"` or `"os.execute"`. Paths which do not exist are
+    /// ignored.
+    pub fn remove(mut self, path: &str) -> StateBuilder {
+        self.removed.push(path.to_string());
+        self
+    }
+
+    /// If `true`, the `load` function of the base library only accepts text chunks, regardless of
+    /// the mode requested by the script. Loading untrusted binary chunks can crash Lua.
+    pub fn text_only_load(mut self, text_only: bool) -> StateBuilder {
+        self.text_only_load = text_only;
+        self
+    }
+
+    /// Creates the state.
+    pub fn build(self) -> State {
+        let mut state = State::new();
+        for lib in &self.libs {
+            state.open_lib(*lib);
+        }
+        for path in &self.removed {
+            remove_path(&mut state, path);
+        }
+        if self.text_only_load && self.libs.contains(&StandardLib::Base) {
+            state.get_global("load");
+            state.push_closure(load_text_only, 1);
+            state.set_global("load");
+        }
+        state
+    }
+}
+
+impl State {
+    /// Opens a single standard library into the state, setting the corresponding global and
+    /// its entry in `package.loaded`.
+    pub fn open_lib(&mut self, lib: StandardLib) {
+        unsafe {
+            ffi::luaL_requiref(self.lua,
+                               lib.name().as_ptr() as *const c_char,
+                               lib.open_fn(),
+                               1);
+        }
+        self.pop(1);
+    }
+}
+
+fn remove_path(state: &mut State, path: &str) {
+    let mut parts: Vec<&str> = path.split('.').collect();
+    let last = parts.pop().unwrap();
+    if parts.is_empty() {
+        state.push_nil();
+        state.set_global(last);
+        return;
+    }
+    let top = state.get_top();
+    if state.get_global(parts[0]) == LuaType::Table {
+        let mut found = true;
+        for part in &parts[1..] {
+            if state.get_field(LuaIndex::Stack(-1), part) != LuaType::Table {
+                found = false;
+                break;
+            }
+        }
+        if found {
+            state.push_nil();
+            state.set_field(LuaIndex::Stack(-2), last);
+        }
+    }
+    state.set_top(top);
+}
+
+// Replacement for `load` which forces the mode argument to "t".
+fn load_text_only(state: &mut State) -> RunResult<u32> {
+    // load (chunk [, chunkname [, mode [, env]]])
+    // The environment argument must stay absent if it wasn't given, as an explicit `nil` sets the
+    // chunk's `_ENV` to `nil`.
+    if state.get_top() < 3 {
+        state.set_top(3);
+    }
+    state.push_string("t");
+    state.replace(AbsIndex(3));
+    let nargs = state.get_top();
+    state.push_value(LuaIndex::Upvalue(1));
+    state.insert(AbsIndex(1));
+    try!(state.call(nargs as u32, LuaCallResults::MultRet));
+    Ok(state.get_top() as u32)
+}
@@ -0,0 +1,236 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use state::{State, HookMask, HookEvent};
+use state::hook::HookGuard;
+
+/// Line coverage of the chunks run while a `CoverageCollector` was active, returned by
+/// `CoverageCollector::stop()`.
+///
+/// Files are named after the chunk names passed to the loading functions, without the leading
+/// `@` or `=`. The executable lines of a chunk are taken from the active lines of each function
+/// which ran, so the bodies of functions which were never called are not known. The lines where
+/// such functions are defined are still reported, as they belong to the enclosing function.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    /// Hit counts of the executable lines, by file.
+    files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl Coverage {
+    /// Returns the names of the files with coverage data, in alphabetical order.
+    pub fn files(&self) -> Vec<&str> {
+        self.files.keys().map(|name| &name[..]).collect()
+    }
+
+    /// Returns the executable lines of a file with the number of times each was executed, in
+    /// ascending order.
+    pub fn lines(&self, file: &str) -> Option<Vec<(u32, u64)>> {
+        self.files.get(file).map(|lines| lines.iter().map(|(&line, &hits)| (line, hits)).collect())
+    }
+
+    /// Returns the number of times a line was executed, or `None` if it is not an executable
+    /// line.
+    pub fn hits(&self, file: &str, line: u32) -> Option<u64> {
+        self.files.get(file).and_then(|lines| lines.get(&line).cloned())
+    }
+
+    /// Returns the number of executed lines and the number of executable lines in all files.
+    pub fn summary(&self) -> (usize, usize) {
+        self.files.values().fold((0, 0), |(hit, found), lines| {
+            let (file_hit, file_found) = line_counts(lines);
+            (hit + file_hit, found + file_found)
+        })
+    }
+
+    /// Adds the coverage data of another run, for example from a different state.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (file, lines) in &other.files {
+            let merged = self.files.entry(file.clone()).or_insert_with(BTreeMap::new);
+            for (&line, &hits) in lines {
+                *merged.entry(line).or_insert(0) += hits;
+            }
+        }
+    }
+
+    /// Returns the coverage in the LCOV tracefile format, as read by `genhtml` and most CI
+    /// services.
+    pub fn to_lcov(&self) -> String {
+        let mut result = String::new();
+        for (file, lines) in &self.files {
+            result.push_str(&format!("TN:\nSF:{}\n", file));
+            for (line, hits) in lines {
+                result.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            let (hit, found) = line_counts(lines);
+            result.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", found, hit));
+        }
+        result
+    }
+
+    /// Returns the coverage as a Cobertura XML report. Each file is reported as a class of a
+    /// single package; branch coverage is not measured.
+    pub fn to_cobertura(&self) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let (hit, found) = self.summary();
+        let mut result = String::new();
+        result.push_str("<?xml version=\"1.0\" ?>\n");
+        result.push_str("<!DOCTYPE coverage SYSTEM \
+                         \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n");
+        result.push_str(&format!("<coverage line-rate=\"{}\" branch-rate=\"0\" \
+                                  lines-covered=\"{}\" lines-valid=\"{}\" branches-covered=\"0\" \
+                                  branches-valid=\"0\" complexity=\"0\" version=\"lowlua\" \
+                                  timestamp=\"{}\">\n",
+                                 line_rate(hit, found),
+                                 hit,
+                                 found,
+                                 timestamp));
+        result.push_str("  <sources>\n    <source>.</source>\n  </sources>\n");
+        result.push_str(&format!("  <packages>\n    <package name=\"lua\" line-rate=\"{}\" \
+                                  branch-rate=\"0\" complexity=\"0\">\n      <classes>\n",
+                                 line_rate(hit, found)));
+        for (file, lines) in &self.files {
+            let (hit, found) = line_counts(lines);
+            result.push_str(&format!("        <class name=\"{0}\" filename=\"{0}\" \
+                                      line-rate=\"{1}\" branch-rate=\"0\" complexity=\"0\">\n",
+                                     xml_escape(file),
+                                     line_rate(hit, found)));
+            result.push_str("          <methods/>\n          <lines>\n");
+            for (line, hits) in lines {
+                result.push_str(&format!("            <line number=\"{}\" hits=\"{}\"/>\n",
+                                         line,
+                                         hits));
+            }
+            result.push_str("          </lines>\n        </class>\n");
+        }
+        result.push_str("      </classes>\n    </package>\n  </packages>\n</coverage>\n");
+        result
+    }
+}
+
+/// A running coverage collector, created by `State::start_coverage()`.
+///
+/// Dropping the collector without calling `stop()` discards the collected data and removes its
+/// hook.
+#[must_use]
+pub struct CoverageCollector {
+    data: Rc<RefCell<CoverageData>>,
+    hook: HookGuard,
+}
+
+impl CoverageCollector {
+    /// Returns the coverage collected so far, without stopping the collector.
+    pub fn snapshot(&self) -> Coverage {
+        self.data.borrow().coverage.clone()
+    }
+
+    /// Stops collecting and returns the coverage.
+    pub fn stop(self, state: &mut State) -> Coverage {
+        state.remove_hook(self.hook.id());
+        self.snapshot()
+    }
+}
+
+struct CoverageData {
+    coverage: Coverage,
+    /// The chunk names and file names of the functions whose active lines have been added, by
+    /// the address of their chunk name and the line where they were defined. The chunk name is
+    /// compared as well, as the address may be reused after the chunk was collected.
+    functions: HashMap<(usize, i32), (Vec<u8>, String)>,
+}
+
+impl CoverageData {
+    fn on_line(&mut self, state: &mut State, line: u32) {
+        let coverage = &mut self.coverage;
+        let functions = &mut self.functions;
+        state.with_stack_level(0, |state, ar| {
+            let (key, known) = {
+                let (source, line_defined) = state.function_source(ar);
+                let key = (source.as_ptr() as usize, line_defined);
+                let known = match functions.get(&key) {
+                    Some(&(ref cached, _)) => &cached[..] == source,
+                    None => false,
+                };
+                (key, known)
+            };
+            if !known {
+                let info = state.get_info(ar, "S");
+                let file = file_name(&info.source);
+                let active_lines = state.get_info(ar, "L").active_lines;
+                let lines = coverage.files.entry(file.clone()).or_insert_with(BTreeMap::new);
+                for active_line in active_lines {
+                    lines.entry(active_line).or_insert(0);
+                }
+                functions.insert(key, (info.source.into_bytes(), file));
+            }
+            let file = &functions[&key].1;
+            let lines = coverage.files.get_mut(file).unwrap();
+            *lines.entry(line).or_insert(0) += 1;
+        });
+    }
+}
+
+impl State {
+    /// Starts recording which lines of the scripts running in this state are executed. The
+    /// collector runs until `CoverageCollector::stop()` is called, and is built on `add_hook()`.
+    ///
+    /// ```ignore
+    /// let collector = state.start_coverage();
+    /// state.do_file::<(), _>("tests/all.lua").unwrap();
+    /// let coverage = collector.stop(&mut state);
+    /// File::create("lcov.info").unwrap().write_all(coverage.to_lcov().as_bytes());
+    /// ```
+    pub fn start_coverage(&mut self) -> CoverageCollector {
+        let data = Rc::new(RefCell::new(CoverageData {
+            coverage: Coverage::default(),
+            functions: HashMap::new(),
+        }));
+        let hook_data = data.clone();
+        let mask = HookMask { line: true, ..HookMask::default() };
+        let hook = self.add_guarded_hook(mask, move |state, event| {
+            if let HookEvent::Line(line) = event {
+                hook_data.borrow_mut().on_line(state, line);
+            }
+            Ok(())
+        });
+        CoverageCollector {
+            data: data,
+            hook: hook,
+        }
+    }
+}
+
+/// Returns the file name for a chunk name.
+fn file_name(source: &str) -> String {
+    if source.starts_with('@') || source.starts_with('=') {
+        source[1..].to_string()
+    } else {
+        source.to_string()
+    }
+}
+
+/// Returns the number of executed lines and the number of executable lines.
+fn line_counts(lines: &BTreeMap<u32, u64>) -> (usize, usize) {
+    (lines.values().filter(|&&hits| hits > 0).count(), lines.len())
+}
+
+fn line_rate(hit: usize, found: usize) -> String {
+    if found == 0 {
+        "1".to_string()
+    } else {
+        format!("{:.4}", hit as f64 / found as f64)
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
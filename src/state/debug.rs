@@ -0,0 +1,390 @@
+use std::ptr;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+
+use libc::{c_char, c_int};
+
+use ffi;
+use state::{State, call_native};
+use ::{RunResult, RunError, LuaIndex};
+
+/// An active function on the call stack, passed to the closure given to
+/// `State::with_stack_level()`.
+///
+/// The record refers to the function's frame, so it can't be kept after the closure returns, when
+/// the function may have returned too.
+pub struct ActivationRecord<'a> {
+    debug: ffi::lua_Debug,
+    level: u32,
+    /// Ties the record to the closure; invariant so that records of different calls can't be
+    /// exchanged.
+    scope: PhantomData<&'a mut &'a ()>,
+}
+
+impl<'a> ActivationRecord<'a> {
+    /// Returns the level of the function on the call stack. The running function is level 0.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+}
+
+/// Identifies an upvalue, as returned by `State::upvalue_id()`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct UpvalueId(usize);
+
+/// Information about a function, returned by `State::get_info()` and
+/// `State::get_function_info()`.
+///
+/// Which fields are filled in depends on the options given to the query, which are the same as
+/// for `lua_getinfo`. Fields which were not requested keep their default values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    /// `S`: The source of the chunk which created the function, such as `@file.lua` or the chunk
+    /// itself if it was loaded from a string.
+    pub source: String,
+    /// `S`: A printable version of `source`, for use in error messages.
+    pub short_src: String,
+    /// `S`: `"Lua"` for a Lua function, `"C"` for a native function or `"main"` for the main
+    /// part of a chunk.
+    pub what: String,
+    /// `S`: The line where the definition of the function starts.
+    pub line_defined: i32,
+    /// `S`: The line where the definition of the function ends.
+    pub last_line_defined: i32,
+    /// `l`: The line which is currently being executed, if known.
+    pub current_line: Option<u32>,
+    /// `n`: A reasonable name for the function, if one could be found.
+    pub name: Option<String>,
+    /// `n`: Explains `name`: `"global"`, `"local"`, `"method"`, `"field"`, `"upvalue"`, or
+    /// empty if no name was found.
+    pub name_what: String,
+    /// `u`: The number of upvalues of the function, not counting the one used internally by
+    /// native closures.
+    pub nups: u32,
+    /// `u`: The number of fixed parameters of the function. Always 0 for native functions.
+    pub nparams: u32,
+    /// `u`: Whether the function is a vararg function. Always `true` for native functions.
+    pub is_vararg: bool,
+    /// `t`: Whether the function was invoked by a tail call.
+    pub is_tail_call: bool,
+    /// `L`: The lines of the function which contain code, in ascending order. Empty for native
+    /// functions.
+    pub active_lines: Vec<u32>,
+}
+
+impl State {
+    /// Calls `f` with the function running at the given level of the call stack and returns its
+    /// result. Level 0 is the running function, level 1 is the function that called it, and so
+    /// on. Returns `None` without calling `f` if the level is not less than the stack depth.
+    ///
+    /// ```ignore
+    /// let caller = state.with_stack_level(1, |state, ar| state.get_info(ar, "Sl"));
+    /// ```
+    pub fn with_stack_level<F, R>(&mut self, level: u32, f: F) -> Option<R>
+        where F: for<'a> FnOnce(&mut State, &mut ActivationRecord<'a>) -> R
+    {
+        let mut debug = ffi::lua_Debug::default();
+        if unsafe { ffi::lua_getstack(self.lua, level as c_int, &mut debug) } == 0 {
+            return None;
+        }
+        let mut ar = ActivationRecord {
+            debug: debug,
+            level: level,
+            scope: PhantomData,
+        };
+        Some(f(self, &mut ar))
+    }
+
+    /// Returns the number of active functions on the call stack.
+    pub fn stack_depth(&self) -> u32 {
+        let mut debug = ffi::lua_Debug::default();
+        let mut depth = 0;
+        while unsafe { ffi::lua_getstack(self.lua, depth as c_int, &mut debug) } != 0 {
+            depth += 1;
+        }
+        depth
+    }
+
+    /// Returns information about the function of an activation record. `what` selects the
+    /// information to fill in, as a combination of the characters `S`, `l`, `n`, `t`, `u` and
+    /// `L` described by `DebugInfo`. If `what` contains `f`, the function itself is pushed onto
+    /// the stack.
+    ///
+    /// Panics if `what` contains any other character.
+    pub fn get_info(&mut self, ar: &mut ActivationRecord, what: &str) -> DebugInfo {
+        check_info_options(what);
+        self.ensure_stack(2);
+        let options = info_options("", what);
+        unsafe {
+            ffi::lua_getinfo(self.lua, options.as_ptr() as *const c_char, &mut ar.debug);
+        }
+        self.read_info(&ar.debug, what)
+    }
+
+    /// Returns the chunk name of the function of an activation record and the line where it was
+    /// defined, as the `S` option of `get_info()` does, but without copying the name. Used where
+    /// `get_info()` is too slow, such as in line hooks.
+    pub(crate) fn function_source<'b>(&mut self, ar: &'b mut ActivationRecord) -> (&'b [u8], i32) {
+        unsafe {
+            ffi::lua_getinfo(self.lua, b"S\0".as_ptr() as *const c_char, &mut ar.debug);
+            (CStr::from_ptr(ar.debug.source).to_bytes(), ar.debug.linedefined as i32)
+        }
+    }
+
+    /// Returns information about the function at the given index, which is not popped. `what` is
+    /// interpreted as for `get_info()`, except that `l` and `t` do not apply, since the function is
+    /// not necessarily running.
+    ///
+    /// Panics if `what` contains an invalid character.
+    pub fn get_function_info(&mut self, idx: LuaIndex, what: &str) -> DebugInfo {
+        check_info_options(what);
+        self.ensure_stack(3);
+        self.push_value(idx);
+        let options = info_options(">", what);
+        let mut debug = ffi::lua_Debug::default();
+        unsafe {
+            ffi::lua_getinfo(self.lua, options.as_ptr() as *const c_char, &mut debug);
+        }
+        self.read_info(&debug, what)
+    }
+
+    /// Pushes the value of local variable `n` of an activation record onto the stack and returns
+    /// its name. Returns `None` and pushes nothing if there is no such local variable.
+    ///
+    /// Parameters and local variables are numbered from 1 in the order they are declared, counting
+    /// only the variables which are active at the current line. Negative numbers refer to vararg
+    /// arguments. Names starting with `(` denote internal variables, such as loop control
+    /// variables and temporaries of native functions.
+    pub fn get_local(&mut self, ar: &ActivationRecord, n: i32) -> Option<String> {
+        self.ensure_stack(1);
+        unsafe { name_to_string(ffi::lua_getlocal(self.lua, &ar.debug, n as c_int)) }
+    }
+
+    /// Pops a value from the stack and assigns it to local variable `n` of an activation record,
+    /// returning the name of the variable. Returns `None` if there is no such local variable, in
+    /// which case the value is still popped. Variables are numbered as for `get_local()`.
+    pub fn set_local(&mut self, ar: &ActivationRecord, n: i32) -> Option<String> {
+        let name = unsafe { name_to_string(ffi::lua_setlocal(self.lua, &ar.debug, n as c_int)) };
+        if name.is_none() {
+            self.pop(1);
+        }
+        name
+    }
+
+    /// Returns the name of parameter `n` of the Lua function at the given index, or `None` if the
+    /// function has no such parameter or is a native function.
+    pub fn parameter_name(&mut self, idx: LuaIndex, n: i32) -> Option<String> {
+        self.push_value(idx);
+        let name = unsafe { name_to_string(ffi::lua_getlocal(self.lua, ptr::null(), n as c_int)) };
+        self.pop(1);
+        name
+    }
+
+    /// Pushes the value of upvalue `n` of the function at the given index onto the stack and
+    /// returns its name. Returns `None` and pushes nothing if the function has no such upvalue.
+    ///
+    /// Upvalues are numbered from 1. For native closures created by `push_closure()`, the numbers
+    /// match `LuaIndex::Upvalue` inside the closure, and the name is always empty.
+    pub fn get_upvalue(&mut self, funcidx: LuaIndex, n: u32) -> Option<String> {
+        self.ensure_stack(1);
+        let n = match self.raw_upvalue_number(funcidx, n) {
+            Some(n) => n,
+            None => return None,
+        };
+        unsafe { name_to_string(ffi::lua_getupvalue(self.lua, funcidx.to_ffi(), n)) }
+    }
+
+    /// Pops a value from the stack and assigns it to upvalue `n` of the function at the given
+    /// index, returning the name of the upvalue. Returns `None` if the function has no such
+    /// upvalue, in which case the value is still popped. Upvalues are numbered as for
+    /// `get_upvalue()`.
+    pub fn set_upvalue(&mut self, funcidx: LuaIndex, n: u32) -> Option<String> {
+        let name = match self.raw_upvalue_number(funcidx, n) {
+            Some(n) => unsafe {
+                name_to_string(ffi::lua_setupvalue(self.lua, funcidx.to_ffi(), n))
+            },
+            None => None,
+        };
+        if name.is_none() {
+            self.pop(1);
+        }
+        name
+    }
+
+    /// Returns the number of the upvalue called `name` of the Lua function at the given index, or
+    /// `None` if there is no such upvalue. Upvalues of native functions have no names.
+    pub fn find_upvalue(&mut self, funcidx: LuaIndex, name: &str) -> Option<u32> {
+        let funcidx = self.abs_index(funcidx);
+        let mut n = 1;
+        while let Some(upvalue) = self.get_upvalue(funcidx, n) {
+            self.pop(1);
+            if upvalue == name {
+                return Some(n);
+            }
+            n += 1;
+        }
+        None
+    }
+
+    /// Like `get_upvalue()`, but looks the upvalue up by name. Returns `false` and pushes nothing
+    /// if there is no such upvalue.
+    pub fn get_upvalue_by_name(&mut self, funcidx: LuaIndex, name: &str) -> bool {
+        match self.find_upvalue(funcidx, name) {
+            Some(n) => self.get_upvalue(funcidx, n).is_some(),
+            None => false,
+        }
+    }
+
+    /// Like `set_upvalue()`, but looks the upvalue up by name. Returns `false` if there is no such
+    /// upvalue, in which case the value is still popped.
+    pub fn set_upvalue_by_name(&mut self, funcidx: LuaIndex, name: &str) -> bool {
+        match self.find_upvalue(funcidx, name) {
+            Some(n) => self.set_upvalue(funcidx, n).is_some(),
+            None => {
+                self.pop(1);
+                false
+            }
+        }
+    }
+
+    /// Returns a unique identifier for upvalue `n` of the function at the given index, or `None`
+    /// if the function has no such upvalue. Closures which share an upvalue, for example because
+    /// they captured the same local variable, return the same identifier for it.
+    pub fn upvalue_id(&mut self, funcidx: LuaIndex, n: u32) -> Option<UpvalueId> {
+        let funcidx = self.abs_index(funcidx);
+        if self.get_upvalue(funcidx, n).is_none() {
+            return None;
+        }
+        self.pop(1);
+        let n = self.raw_upvalue_number(funcidx, n).unwrap();
+        let id = unsafe { ffi::lua_upvalueid(self.lua, funcidx.to_ffi(), n) };
+        Some(UpvalueId(id as usize))
+    }
+
+    /// Makes upvalue `n1` of the Lua function at `f1` refer to upvalue `n2` of the Lua function
+    /// at `f2`, so that both closures share the same variable from then on. Returns an error if
+    /// either function is not a Lua function or does not have the given upvalue.
+    pub fn upvalue_join(&mut self,
+                        f1: LuaIndex,
+                        n1: u32,
+                        f2: LuaIndex,
+                        n2: u32)
+                        -> RunResult<()> {
+        for &(idx, n) in &[(f1, n1), (f2, n2)] {
+            if !self.is_function(idx) || self.is_native_function(idx) {
+                return Err(RunError::new("cannot join upvalues of a non-Lua function".to_string(),
+                                         self.backtrace()));
+            }
+            if self.upvalue_id(idx, n).is_none() {
+                return Err(RunError::new(format!("invalid upvalue index {}", n),
+                                         self.backtrace()));
+            }
+        }
+        unsafe {
+            ffi::lua_upvaluejoin(self.lua, f1.to_ffi(), n1 as c_int, f2.to_ffi(), n2 as c_int);
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the value at the given index is a closure created by `push_closure()`.
+    fn is_native_closure(&self, idx: LuaIndex) -> bool {
+        unsafe {
+            ffi::lua_iscfunction(self.lua, idx.to_ffi()) != 0 &&
+            ffi::lua_tocfunction(self.lua, idx.to_ffi()) as usize == call_native as usize
+        }
+    }
+
+    /// Converts an upvalue number as seen by users to the one used by the C API, skipping the
+    /// upvalue reserved by `push_closure()`.
+    fn raw_upvalue_number(&self, funcidx: LuaIndex, n: u32) -> Option<c_int> {
+        if n == 0 {
+            None
+        } else if self.is_native_closure(funcidx) {
+            Some(n as c_int + 1)
+        } else {
+            Some(n as c_int)
+        }
+    }
+
+    /// Converts a `lua_Debug` filled in by `lua_getinfo` with the options returned by
+    /// `info_options()`, popping the values which were pushed but not requested.
+    fn read_info(&mut self, debug: &ffi::lua_Debug, what: &str) -> DebugInfo {
+        let mut info = DebugInfo::default();
+        unsafe {
+            if what.contains('S') {
+                info.source = name_to_string(debug.source).unwrap_or_else(String::new);
+                info.short_src = CStr::from_ptr(debug.short_src.as_ptr())
+                    .to_string_lossy()
+                    .into_owned();
+                info.what = name_to_string(debug.what).unwrap_or_else(String::new);
+                info.line_defined = debug.linedefined;
+                info.last_line_defined = debug.lastlinedefined;
+            }
+            if what.contains('l') && debug.currentline > 0 {
+                info.current_line = Some(debug.currentline as u32);
+            }
+            if what.contains('n') {
+                info.name = name_to_string(debug.name);
+                info.name_what = name_to_string(debug.namewhat).unwrap_or_else(String::new);
+            }
+        }
+        if what.contains('u') {
+            info.nups = debug.nups as u32;
+            // The function was pushed below the active lines, so its upvalues can be counted
+            // without the one used internally by native closures
+            if self.is_native_closure(LuaIndex::Stack(if what.contains('L') { -2 } else { -1 })) {
+                info.nups -= 1;
+            }
+            info.nparams = debug.nparams as u32;
+            info.is_vararg = debug.isvararg != 0;
+        }
+        if what.contains('t') {
+            info.is_tail_call = debug.istailcall != 0;
+        }
+        if what.contains('L') {
+            // Native functions push nil instead of a table
+            let lines = self.abs_index(LuaIndex::Stack(-1));
+            if self.is_table(lines) {
+                self.push_nil();
+                while self.next(lines) {
+                    if let Ok(line) = self.at::<i64>(LuaIndex::Stack(-2)) {
+                        info.active_lines.push(line as u32);
+                    }
+                    self.pop(1);
+                }
+                info.active_lines.sort();
+            }
+            self.pop(1);
+        }
+        if what.contains('u') && !what.contains('f') {
+            self.pop(1);
+        }
+        info
+    }
+}
+
+/// Returns the options passed to `lua_getinfo`. The function is always requested along with its
+/// upvalue count, so `read_info()` can recognize native closures.
+fn info_options(prefix: &str, what: &str) -> String {
+    if what.contains('u') && !what.contains('f') {
+        format!("{}f{}\0", prefix, what)
+    } else {
+        format!("{}{}\0", prefix, what)
+    }
+}
+
+/// Panics if `what` is not a valid set of options for `get_info()`.
+fn check_info_options(what: &str) {
+    if let Some(c) = what.chars().find(|&c| !"SlntuLf".contains(c)) {
+        panic!("invalid debug info option '{}'", c);
+    }
+}
+
+/// Converts a string returned by the debug interface, which may be null.
+unsafe fn name_to_string(name: *const c_char) -> Option<String> {
+    if name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+    }
+}
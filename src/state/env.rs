@@ -0,0 +1,134 @@
+use std::io;
+
+use ffi;
+use state::State;
+use ::{LoadResult, RunResult, RunError, LuaIndex, RelIndex, LoadMode};
+
+impl State {
+    /// Pops a table from the stack and sets it as the environment of the function at the given
+    /// index, that is, as the value of its first upvalue `_ENV`.
+    ///
+    /// Main chunks created by the loading functions always have `_ENV` as their first upvalue. For
+    /// other functions, the first upvalue may be an ordinary local variable, so use caution. If
+    /// the function has no upvalues, the table is simply popped.
+    pub fn set_env(&mut self, funcidx: LuaIndex) {
+        unsafe {
+            if ffi::lua_setupvalue(self.lua, funcidx.to_ffi(), 1).is_null() {
+                ffi::lua_pop(self.lua, 1);
+            }
+        }
+    }
+
+    /// Like `load_string()`, but the loaded chunk uses the table at `env` as its global
+    /// environment instead of the global table.
+    pub fn load_string_with_env(&mut self,
+                                str: &str,
+                                chunkname: &str,
+                                env: LuaIndex,
+                                mode: LoadMode)
+                                -> LoadResult<()> {
+        let env = self.abs_index(env);
+        try!(self.load_string(str, chunkname, mode));
+        self.push_value(env);
+        self.set_env(LuaIndex::Stack(-2));
+        Ok(())
+    }
+
+    /// Like `load_stream()`, but the loaded chunk uses the table at `env` as its global
+    /// environment instead of the global table.
+    pub fn load_stream_with_env<R: io::Read>(&mut self,
+                                             stream: R,
+                                             chunkname: &str,
+                                             env: LuaIndex,
+                                             mode: LoadMode)
+                                             -> LoadResult<()> {
+        let env = self.abs_index(env);
+        try!(self.load_stream(stream, chunkname, mode));
+        self.push_value(env);
+        self.set_env(LuaIndex::Stack(-2));
+        Ok(())
+    }
+
+    /// Creates a new empty environment table and pushes it onto the stack. Reads of names which
+    /// are not defined in the new table fall through to the table at `base`, while assignments
+    /// always go to the new table. This gives each chunk loaded with the environment its own
+    /// global namespace on top of a shared set of globals.
+    ///
+    /// To prevent scripts from modifying the shared table through other means, such as `rawset`,
+    /// pass a read-only view created by `push_read_only()` as `base`.
+    pub fn new_env(&mut self, base: LuaIndex) {
+        let base = self.abs_index(base);
+        self.new_table();
+        self.new_table();
+        self.push_value(base);
+        self.set_field(LuaIndex::Stack(-2), "__index");
+        self.set_metatable(LuaIndex::Stack(-2));
+    }
+
+    /// Pushes a read-only view of the table at the given index onto the stack.
+    ///
+    /// The view is an empty proxy table which forwards reads, `pairs()` and the length operator
+    /// to the original table and raises an error on assignment. The length is the raw length of
+    /// the original table. Its metatable is protected from `getmetatable` and `setmetatable`.
+    /// Note that tables stored inside the original table are not protected themselves.
+    pub fn push_read_only(&mut self, idx: LuaIndex) {
+        let idx = self.abs_index(idx);
+        self.new_table();
+        self.new_table();
+        self.push_value(idx);
+        self.set_field(LuaIndex::Stack(-2), "__index");
+        self.push_function(read_only_newindex);
+        self.set_field(LuaIndex::Stack(-2), "__newindex");
+        self.push_function(read_only_pairs);
+        self.set_field(LuaIndex::Stack(-2), "__pairs");
+        self.push_function(read_only_len);
+        self.set_field(LuaIndex::Stack(-2), "__len");
+        self.push_boolean(false);
+        self.set_field(LuaIndex::Stack(-2), "__metatable");
+        self.set_metatable(LuaIndex::Stack(-2));
+    }
+}
+
+fn read_only_newindex(state: &mut State) -> RunResult<u32> {
+    let message = match state.at::<String>(LuaIndex::Stack(2)) {
+        Ok(key) => format!("attempt to modify field '{}' of a read-only table", key),
+        Err(_) => "attempt to modify a read-only table".to_string(),
+    };
+    Err(RunError::new(message, state.backtrace()))
+}
+
+/// Returns an iterator over the original table of a read-only view, which is passed the view
+/// instead of the original table so that scripts can't get hold of it.
+fn read_only_pairs(state: &mut State) -> RunResult<u32> {
+    state.push_function(read_only_next);
+    state.push_value(LuaIndex::Stack(1));
+    state.push_nil();
+    Ok(3)
+}
+
+fn read_only_next(state: &mut State) -> RunResult<u32> {
+    state.set_top(2);
+    push_original(state, LuaIndex::Stack(1));
+    state.push_value(LuaIndex::Stack(2));
+    if state.next(LuaIndex::Stack(3)) {
+        Ok(2)
+    } else {
+        state.push_nil();
+        Ok(1)
+    }
+}
+
+fn read_only_len(state: &mut State) -> RunResult<u32> {
+    push_original(state, LuaIndex::Stack(1));
+    let len = state.raw_len(LuaIndex::Stack(-1));
+    state.push(len as i64);
+    Ok(1)
+}
+
+/// Pushes the original table of the read-only view at the given index.
+fn push_original(state: &mut State, view: LuaIndex) {
+    state.get_metatable(view);
+    state.push_string("__index");
+    state.raw_get(LuaIndex::Stack(-2));
+    state.remove(RelIndex(-2));
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use ffi;
+use state::State;
+use ::RunResult;
+
+/// Settings of the garbage collector, applied with `State::gc_configure()`. Fields which are
+/// `None` are left unchanged.
+///
+/// Lua 5.3 only has an incremental collector, so there are no settings for a generational mode.
+/// See [the manual](https://www.lua.org/manual/5.3/manual.html#2.5) for the meaning of the pause
+/// and the step multiplier.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcConfig {
+    /// Whether the collector runs automatically.
+    pub running: Option<bool>,
+    /// How long the collector waits before starting a new cycle, as a percentage of the memory in
+    /// use after the previous collection. The default is 200.
+    pub pause: Option<i32>,
+    /// The speed of the collector relative to memory allocation, as a percentage. The default is
+    /// 200.
+    pub step_mul: Option<i32>,
+}
+
+/// Restarts the collector when dropped, so that it is restarted even if the guarded code panics.
+struct RestartGuard {
+    lua: *mut ffi::lua_State,
+}
+
+impl Drop for RestartGuard {
+    fn drop(&mut self) {
+        unsafe { ffi::lua_gc(self.lua, ffi::LUA_GCRESTART, 0) };
+    }
+}
+
+impl State {
+    /// Returns the current settings of the garbage collector, with every field set.
+    pub fn gc_config(&mut self) -> GcConfig {
+        // Lua has no way to query the parameters other than setting them
+        let pause = self.gc_set_pause(0);
+        self.gc_set_pause(pause);
+        let step_mul = self.gc_set_step_mul(0);
+        self.gc_set_step_mul(step_mul);
+        GcConfig {
+            running: Some(self.gc_is_running()),
+            pause: Some(pause),
+            step_mul: Some(step_mul),
+        }
+    }
+
+    /// Applies all the given settings at once, and returns the previous settings, with every field
+    /// set, so they can be restored later by passing them to this function again.
+    pub fn gc_configure(&mut self, config: GcConfig) -> GcConfig {
+        let previous = self.gc_config();
+        if let Some(pause) = config.pause {
+            self.gc_set_pause(pause);
+        }
+        if let Some(step_mul) = config.step_mul {
+            self.gc_set_step_mul(step_mul);
+        }
+        match config.running {
+            Some(true) => self.gc_restart(),
+            Some(false) => self.gc_stop(),
+            None => {}
+        }
+        previous
+    }
+
+    /// Runs `f` with the garbage collector stopped, for example to keep a latency-sensitive
+    /// section free of collection work. The collector is restarted afterwards, even if `f`
+    /// panics, unless it was already stopped before. Memory keeps growing while the collector is
+    /// stopped, so keep such sections short.
+    pub fn with_gc_stopped<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut State) -> R
+    {
+        if !self.gc_is_running() {
+            return f(self);
+        }
+        self.gc_stop();
+        let _guard = RestartGuard { lua: self.lua };
+        f(self)
+    }
+
+    /// Performs incremental garbage collection steps until `budget` has elapsed or a collection
+    /// cycle finishes, whichever happens first. This is meant to be called once per frame, usually
+    /// with the collector stopped, to spread collection work evenly over frames.
+    ///
+    /// Returns `true` if a cycle finished, or an error if a finalizer fails. At least one step is
+    /// always performed, and a step may overrun the budget slightly.
+    ///
+    /// ```ignore
+    /// state.gc_stop();
+    /// loop {
+    ///     update(&mut state);
+    ///     state.gc_step_for(Duration::from_millis(1)).unwrap();
+    /// }
+    /// ```
+    pub fn gc_step_for(&mut self, budget: Duration) -> RunResult<bool> {
+        let start = Instant::now();
+        loop {
+            if try!(self.gc_step(0)) {
+                return Ok(true);
+            }
+            if start.elapsed() >= budget {
+                return Ok(false);
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::thread;
+use std::ops::{Deref, DerefMut};
+
+use state::State;
+use ::LuaIndex;
+
+/// What a `StackGuard` does with the stack when it is dropped.
+enum GuardMode {
+    Restore,
+    Check,
+}
+
+/// A guard which watches the top of the stack, created by `State::stack_guard()` or
+/// `State::stack_check()`.
+///
+/// The guard dereferences to the `State` it was created from, so it can be used in its place for
+/// the duration of the guarded region.
+pub struct StackGuard<'a> {
+    state: &'a mut State,
+    top: i32,
+    mode: GuardMode,
+}
+
+impl<'a> StackGuard<'a> {
+    /// Returns the top of the stack at the time the guard was created.
+    pub fn saved_top(&self) -> i32 {
+        self.top
+    }
+}
+
+impl<'a> Deref for StackGuard<'a> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        self.state
+    }
+}
+
+impl<'a> DerefMut for StackGuard<'a> {
+    fn deref_mut(&mut self) -> &mut State {
+        self.state
+    }
+}
+
+impl<'a> Drop for StackGuard<'a> {
+    fn drop(&mut self) {
+        match self.mode {
+            GuardMode::Restore => self.state.set_top(self.top),
+            GuardMode::Check => {
+                // Panicking while unwinding would abort the process
+                let top = self.state.get_top();
+                if cfg!(debug_assertions) && top != self.top && !thread::panicking() {
+                    panic!("unbalanced stack: top was {} but is now {}", self.top, top);
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    /// Returns a guard which resets the top of the stack to its current position when dropped,
+    /// discarding any values pushed in the meantime. This makes it possible to use the `try!`
+    /// macro freely in code which pushes temporary values.
+    ///
+    /// If values below the saved top were popped, they are replaced with `nil`.
+    pub fn stack_guard(&mut self) -> StackGuard {
+        let top = self.get_top();
+        StackGuard {
+            state: self,
+            top: top,
+            mode: GuardMode::Restore,
+        }
+    }
+
+    /// Returns a guard which asserts that the top of the stack is back at its current position
+    /// when dropped. The check is only performed when debug assertions are enabled.
+    pub fn stack_check(&mut self) -> StackGuard {
+        let top = self.get_top();
+        StackGuard {
+            state: self,
+            top: top,
+            mode: GuardMode::Check,
+        }
+    }
+
+    /// Enables or disables strict checking of the results of native functions. Lua discards the
+    /// values a native function leaves below its results, but when strict checking is enabled,
+    /// leaving any values other than the arguments there raises an error, to help find functions
+    /// which push more than they meant to. The check is only performed when debug assertions are
+    /// enabled.
+    ///
+    /// The check compares the number of values below the results with the number of arguments,
+    /// so a function which pops some of its arguments may leave as many other values there
+    /// without raising an error.
+    pub fn set_strict_native_results(&mut self, strict: bool) {
+        self.get_internal_registry();
+        if strict {
+            self.push(true);
+        } else {
+            self.push_nil();
+        }
+        self.set_field(LuaIndex::Stack(-2), "strict");
+        self.pop(1);
+    }
+}
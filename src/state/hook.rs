@@ -0,0 +1,280 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use libc::c_int;
+
+use ffi;
+use state::{State, raise_after_drop};
+use ::{RunResult, LuaIndex};
+
+/// An event for which a hook is called.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HookEvent {
+    /// A function was called. The called function is at stack level 0.
+    Call,
+    /// A function was called as a tail call. The caller's activation record was replaced by the
+    /// called function, so there is no corresponding `Return` event for the caller.
+    TailCall,
+    /// A function is about to return. The returning function is at stack level 0.
+    Return,
+    /// The interpreter is about to execute a new line of code, or has jumped back in the code,
+    /// even to the same line. Only sent while running Lua functions.
+    Line(u32),
+    /// The interpreter has executed the number of instructions requested by the hook.
+    Count,
+}
+
+/// Selects the events for which a hook is called.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct HookMask {
+    /// Call the hook when a function is called, including tail calls.
+    pub call: bool,
+    /// Call the hook when a function returns.
+    pub ret: bool,
+    /// Call the hook for every line of Lua code executed.
+    pub line: bool,
+    /// Call the hook after every `n` VM instructions. `n` must not be 0.
+    pub count: Option<u32>,
+}
+
+/// Identifies a hook added with `State::add_hook()`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct HookId(u64);
+
+/// Removes a hook when dropped, for values which own a hook but can't reach the state when they are
+/// dropped. The hook is disabled at once, and removed by the next call to `add_hook()`,
+/// `remove_hook()` or the dispatcher.
+pub struct HookGuard(Rc<HookEntry>);
+
+impl HookGuard {
+    /// Returns the identifier of the hook, which can be passed to `State::remove_hook()` to
+    /// remove it right away.
+    pub fn id(&self) -> HookId {
+        self.0.id
+    }
+}
+
+impl Drop for HookGuard {
+    fn drop(&mut self) {
+        self.0.active.set(false);
+    }
+}
+
+type HookFunction = FnMut(&mut State, HookEvent) -> RunResult<()>;
+
+struct HookEntry {
+    id: HookId,
+    mask: HookMask,
+    /// Instructions left until a count hook is due.
+    remaining: Cell<u32>,
+    /// Cleared when the hook is removed, as the dispatcher may still hold a reference to it.
+    active: Cell<bool>,
+    f: RefCell<Box<HookFunction>>,
+}
+
+impl HookEntry {
+    fn wants(&self, event: HookEvent, interval: u32) -> bool {
+        match event {
+            HookEvent::Call | HookEvent::TailCall => self.mask.call,
+            HookEvent::Return => self.mask.ret,
+            HookEvent::Line(_) => self.mask.line,
+            HookEvent::Count => {
+                match self.mask.count {
+                    Some(count) => {
+                        let remaining = self.remaining.get().saturating_sub(interval);
+                        if remaining == 0 {
+                            self.remaining.set(count);
+                            true
+                        } else {
+                            self.remaining.set(remaining);
+                            false
+                        }
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+/// The hooks of a state, stored in the internal registry.
+struct Hooks {
+    entries: Vec<Rc<HookEntry>>,
+    /// The mask and instruction count the Lua hook is installed with.
+    mask: c_int,
+    interval: u32,
+    next_id: u64,
+}
+
+impl State {
+    /// Adds a function which is called for the events selected by `mask`, and returns an
+    /// identifier which can be used to remove it again.
+    ///
+    /// Lua only supports a single hook per thread, so lowlua installs a dispatcher which calls
+    /// every hook added to the state, in the order they were added. Use this function instead of
+    /// setting a hook through the C API, which would replace the dispatcher. Features such as
+    /// `interrupt_handle()` are built on top of it.
+    ///
+    /// While a hook runs, Lua does not call any hooks, so a hook can safely call functions. If a
+    /// hook returns an error or panics, the error is raised in the running script like an error
+    /// from a native function.
+    ///
+    /// Hooks are installed on the main thread and the running thread. Coroutines created while
+    /// any hook is installed inherit the dispatcher, and pick up hooks added or removed later at
+    /// their next hook event. Lua provides no way to find the other threads of a state, so
+    /// coroutines created while no hook was installed never call hooks.
+    ///
+    /// Panics if `mask.count` is `Some(0)`.
+    pub fn add_hook<F>(&mut self, mask: HookMask, f: F) -> HookId
+        where F: FnMut(&mut State, HookEvent) -> RunResult<()> + 'static
+    {
+        self.push_hook(mask, Box::new(f)).id
+    }
+
+    /// Adds a hook like `add_hook()`, and returns a guard which removes it when dropped.
+    pub(crate) fn add_guarded_hook<F>(&mut self, mask: HookMask, f: F) -> HookGuard
+        where F: FnMut(&mut State, HookEvent) -> RunResult<()> + 'static
+    {
+        HookGuard(self.push_hook(mask, Box::new(f)))
+    }
+
+    /// Removes a hook added with `add_hook()`. Returns `false` if there is no such hook. A hook
+    /// may remove itself while it is running.
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        let hooks = unsafe { &mut *self.get_hooks() };
+        match hooks.entries.iter().position(|entry| entry.id == id) {
+            Some(pos) => {
+                hooks.entries.remove(pos).active.set(false);
+                self.update_hook(hooks);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn push_hook(&mut self, mask: HookMask, f: Box<HookFunction>) -> Rc<HookEntry> {
+        assert!(mask.count != Some(0), "hook instruction count must not be 0");
+        let hooks = unsafe { &mut *self.get_hooks() };
+        let entry = Rc::new(HookEntry {
+            id: HookId(hooks.next_id),
+            mask: mask,
+            remaining: Cell::new(mask.count.unwrap_or(0)),
+            active: Cell::new(true),
+            f: RefCell::new(f),
+        });
+        hooks.next_id += 1;
+        hooks.entries.push(entry.clone());
+        self.update_hook(hooks);
+        entry
+    }
+
+    /// Returns the hooks of this state, creating them if necessary. The userdata is anchored in
+    /// the internal registry, so the pointer remains valid until the state is closed.
+    fn get_hooks(&mut self) -> *mut Hooks {
+        self.get_internal_registry();
+        self.get_field(LuaIndex::Stack(-1), "hooks");
+        let hooks = match self.userdata_at::<Hooks>(LuaIndex::Stack(-1)) {
+            Ok(hooks) => hooks as *mut Hooks,
+            Err(_) => {
+                self.pop(1);
+                self.push_userdata(Hooks {
+                    entries: Vec::new(),
+                    mask: 0,
+                    interval: 0,
+                    next_id: 0,
+                });
+                self.push_value(LuaIndex::Stack(-1));
+                self.set_field(LuaIndex::Stack(-3), "hooks");
+                self.userdata_at::<Hooks>(LuaIndex::Stack(-1)).unwrap() as *mut Hooks
+            }
+        };
+        self.pop(2);
+        hooks
+    }
+
+    /// Removes the hooks disabled by their `HookGuard`, then installs the dispatcher on the main
+    /// thread and the running thread with the union of the masks of all hooks, or removes it if
+    /// there are no hooks. Other threads are updated by the dispatcher.
+    fn update_hook(&mut self, hooks: &mut Hooks) {
+        hooks.entries.retain(|entry| entry.active.get());
+        let mut mask = 0;
+        let mut interval = 0;
+        for entry in &hooks.entries {
+            if entry.mask.call {
+                mask |= ffi::LUA_MASKCALL;
+            }
+            if entry.mask.ret {
+                mask |= ffi::LUA_MASKRET;
+            }
+            if entry.mask.line {
+                mask |= ffi::LUA_MASKLINE;
+            }
+            if let Some(count) = entry.mask.count {
+                mask |= ffi::LUA_MASKCOUNT;
+                interval = gcd(interval, count);
+            }
+        }
+        hooks.mask = mask;
+        hooks.interval = interval;
+        self.ensure_stack(1);
+        unsafe {
+            ffi::lua_rawgeti(self.lua,
+                             ffi::LUA_REGISTRYINDEX,
+                             ffi::LUA_RIDX_MAINTHREAD as ffi::lua_Integer);
+            let main = ffi::lua_tothread(self.lua, -1);
+            ffi::lua_pop(self.lua, 1);
+            ffi::lua_sethook(main, dispatch, mask, interval as c_int);
+            ffi::lua_sethook(self.lua, dispatch, mask, interval as c_int);
+        }
+    }
+}
+
+/// The interval of the count hook, at which every count hook is due after an exact number of
+/// intervals.
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+extern "C" fn dispatch(lua: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+    raise_after_drop(lua, |state| {
+        let event = unsafe {
+            match (*ar).event {
+                ffi::LUA_HOOKCALL => HookEvent::Call,
+                ffi::LUA_HOOKTAILCALL => HookEvent::TailCall,
+                ffi::LUA_HOOKRET => HookEvent::Return,
+                ffi::LUA_HOOKLINE => HookEvent::Line((*ar).currentline as u32),
+                _ => HookEvent::Count,
+            }
+        };
+        // Hooks may be added or removed while the dispatcher runs, so iterate over a copy
+        let (entries, interval) = {
+            let hooks = unsafe { &mut *state.get_hooks() };
+            if hooks.entries.iter().any(|entry| !entry.active.get()) {
+                state.update_hook(hooks);
+            }
+            // Coroutines inherit the dispatcher with the hooks installed when they were created
+            let (mask, interval) = unsafe {
+                (ffi::lua_gethookmask(state.lua), ffi::lua_gethookcount(state.lua) as u32)
+            };
+            if mask != hooks.mask || interval != hooks.interval {
+                unsafe {
+                    ffi::lua_sethook(state.lua, dispatch, hooks.mask, hooks.interval as c_int)
+                };
+            }
+            (hooks.entries.clone(), interval)
+        };
+        for entry in &entries {
+            if !entry.active.get() || !entry.wants(event, interval) {
+                continue;
+            }
+            let mut f = entry.f.borrow_mut();
+            match panic::catch_unwind(AssertUnwindSafe(|| (&mut *f)(&mut *state, event))) {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => state.push_userdata(err),
+                Err(err) => state.push_userdata(err),
+            }
+            return Err(());
+        }
+        Ok(0)
+    });
+}
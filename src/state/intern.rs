@@ -0,0 +1,241 @@
+use std::{slice, str};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use libc::size_t;
+
+use ffi;
+use state::{State, ReleaseQueue};
+use state::traits::{ToLua, FromLua};
+use ::{RunResult, RunError, LuaType, LuaIndex, RelIndex};
+
+/// An interned Lua string.
+///
+/// The string is kept alive by an entry in lowlua's registry while any clone of the `LuaString`
+/// exists. After the last clone is dropped, the entry is removed the next time the state interns
+/// or pushes a string, unless the string was interned with `State::intern_permanent()`. A
+/// `LuaString` must only be pushed onto the state that created it, but it keeps a copy of its
+/// contents, so it can still be compared and hashed after the state was closed.
+pub struct LuaString {
+    ptr: usize,
+    bytes: Rc<[u8]>,
+    interner: Rc<Interner>,
+}
+
+impl LuaString {
+    /// Returns the length of the string in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns `true` if the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the contents of the string, without pushing it onto the stack.
+    pub fn as_bytes<'a>(&'a self, _state: &'a State) -> &'a [u8] {
+        &self.bytes
+    }
+
+    /// Returns the contents of the string, or `None` if it is not valid UTF-8.
+    pub fn as_str<'a>(&'a self, state: &'a State) -> Option<&'a str> {
+        str::from_utf8(self.as_bytes(state)).ok()
+    }
+}
+
+impl Clone for LuaString {
+    fn clone(&self) -> LuaString {
+        self.interner.retain(self.ptr);
+        LuaString {
+            ptr: self.ptr,
+            bytes: self.bytes.clone(),
+            interner: self.interner.clone(),
+        }
+    }
+}
+
+impl Drop for LuaString {
+    fn drop(&mut self) {
+        self.interner.release(self.ptr);
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &LuaString) -> bool {
+        // Short strings are unique in Lua, but equal long strings may be different objects
+        (self.ptr == other.ptr && Rc::ptr_eq(&self.interner, &other.interner)) ||
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for LuaString {}
+
+impl PartialEq<str> for LuaString {
+    fn eq(&self, other: &str) -> bool {
+        &self.bytes[..] == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for LuaString {
+    fn eq(&self, other: &&'a str) -> bool {
+        *self == **other
+    }
+}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bytes.hash(state);
+    }
+}
+
+impl ToLua for LuaString {
+    fn to_lua(&self, state: &mut State) {
+        state.push_interned(self);
+    }
+}
+
+impl<'a> ToLua for &'a LuaString {
+    fn to_lua(&self, state: &mut State) {
+        state.push_interned(self);
+    }
+}
+
+impl FromLua for LuaString {
+    fn from_lua(state: &mut State, idx: LuaIndex) -> RunResult<LuaString> {
+        state.intern_at(idx, false)
+    }
+}
+
+/// Reference counts of the interned strings of a state, shared with its `LuaString`s.
+struct Interner {
+    /// The number of live `LuaString`s by string pointer.
+    counts: RefCell<HashMap<usize, usize>>,
+    /// Strings which are never released.
+    permanent: RefCell<HashSet<usize>>,
+    /// Strings whose last `LuaString` was dropped since the state last removed them.
+    released: ReleaseQueue<usize>,
+}
+
+impl Interner {
+    fn retain(&self, ptr: usize) {
+        *self.counts.borrow_mut().entry(ptr).or_insert(0) += 1;
+    }
+
+    fn release(&self, ptr: usize) {
+        let mut counts = self.counts.borrow_mut();
+        let remaining = {
+            let count = counts.get_mut(&ptr).unwrap();
+            *count -= 1;
+            *count
+        };
+        if remaining == 0 {
+            counts.remove(&ptr);
+            if !self.permanent.borrow().contains(&ptr) {
+                self.released.release(ptr);
+            }
+        }
+    }
+
+    fn is_live(&self, ptr: usize) -> bool {
+        self.counts.borrow().contains_key(&ptr) || self.permanent.borrow().contains(&ptr)
+    }
+}
+
+impl State {
+    /// Creates a LuaString from the passed value. The string is stored in the registry until the
+    /// last clone of the returned `LuaString` is dropped.
+    pub fn intern(&mut self, s: &str) -> LuaString {
+        self.push(s);
+        let result = self.intern_at(LuaIndex::Stack(-1), false).unwrap();
+        self.pop(1);
+        result
+    }
+
+    /// Creates a LuaString from the passed value, which is stored in the registry until the state
+    /// is closed. Use this for strings which are used often, such as common table keys, so that
+    /// they don't have to be interned again after every release.
+    pub fn intern_permanent(&mut self, s: &str) -> LuaString {
+        self.push(s);
+        let result = self.intern_at(LuaIndex::Stack(-1), true).unwrap();
+        self.pop(1);
+        result
+    }
+
+    /// Returns the number of strings currently stored in the registry by `intern()`,
+    /// `intern_permanent()` and conversions to `LuaString`.
+    pub fn interned_strings(&mut self) -> usize {
+        let interner = self.get_interner();
+        self.pop(1);
+        let counts = interner.counts.borrow();
+        let permanent = interner.permanent.borrow();
+        counts.len() + permanent.iter().filter(|&&ptr| !counts.contains_key(&ptr)).count()
+    }
+
+    fn intern_at(&mut self, idx: LuaIndex, permanent: bool) -> RunResult<LuaString> {
+        let idx = self.abs_index(idx);
+        let mut len: size_t = 0;
+        let ptr = unsafe { ffi::lua_tolstring(self.lua, idx.to_ffi(), &mut len as *mut size_t) };
+        if ptr.is_null() {
+            return Err(RunError::conversion_from_lua(self.type_at(idx),
+                                                     "LuaString",
+                                                     self.backtrace()));
+        }
+        let ptr = ptr as usize;
+        let interner = self.get_interner();
+        self.push_unsigned(ptr as u64);
+        self.push_value(idx);
+        self.raw_set(LuaIndex::Stack(-3));
+        self.pop(1);
+        interner.retain(ptr);
+        if permanent {
+            interner.permanent.borrow_mut().insert(ptr);
+        }
+        let bytes = unsafe { slice::from_raw_parts(ptr as *const u8, len as usize) };
+        Ok(LuaString {
+            ptr: ptr,
+            bytes: Rc::from(bytes),
+            interner: interner,
+        })
+    }
+
+    fn push_interned(&mut self, s: &LuaString) {
+        let interner = self.get_interner();
+        assert!(Rc::ptr_eq(&interner, &s.interner),
+                "LuaString used with a state other than the one that created it");
+        self.push_unsigned(s.ptr as u64);
+        self.raw_get(LuaIndex::Stack(-2));
+        self.remove(RelIndex(-2));
+    }
+
+    /// Pushes the table of interned strings, and removes the strings which were released since
+    /// the last call.
+    fn get_interner(&mut self) -> Rc<Interner> {
+        self.ensure_stack(3);
+        self.get_internal_registry();
+        if self.get_field(LuaIndex::Stack(-1), "interner") != LuaType::Userdata {
+            self.pop(1);
+            self.push_userdata(Rc::new(Interner {
+                counts: RefCell::new(HashMap::new()),
+                permanent: RefCell::new(HashSet::new()),
+                released: ReleaseQueue::new(),
+            }));
+            self.push_value(LuaIndex::Stack(-1));
+            self.set_field(LuaIndex::Stack(-3), "interner");
+        }
+        let interner = self.userdata_at::<Rc<Interner>>(LuaIndex::Stack(-1)).unwrap().clone();
+        self.pop(1);
+        self.get_field(LuaIndex::Stack(-1), "string");
+        self.remove(RelIndex(-2));
+        for ptr in interner.released.take() {
+            // The string may have been interned again in the meantime
+            if !interner.is_live(ptr) {
+                self.push_unsigned(ptr as u64);
+                self.push_nil();
+                self.raw_set(LuaIndex::Stack(-3));
+            }
+        }
+        interner
+    }
+}
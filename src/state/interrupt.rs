@@ -0,0 +1,69 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use state::{State, HookMask};
+use ::{RunError, LuaIndex};
+
+/// The number of VM instructions executed between checks of the interrupt flag.
+const INTERRUPT_CHECK_INTERVAL: u32 = 1000;
+
+/// A handle which can be used to cancel a script running in a `State` from any thread.
+///
+/// Calling `interrupt()` sets a flag which is checked periodically by a hook installed by lowlua.
+/// The next time the hook runs, the script is aborted with an "interrupted" `RunError`, which is
+/// returned from `State::call()` like any other runtime error. The flag is cleared when the error
+/// is raised, so the state can be reused afterwards.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Requests that the running script be interrupted at the next safe point. If no script is
+    /// running, the next script to run will be interrupted instead.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if an interrupt has been requested but not yet delivered.
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+impl State {
+    /// Returns a handle which can be used to interrupt scripts running in this state from another
+    /// thread.
+    ///
+    /// The first call adds the interrupt hook with `add_hook()`, so the coroutines it reaches are
+    /// described there. All handles returned by this function share the same flag.
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.get_internal_registry();
+        self.get_field(LuaIndex::Stack(-1), "interrupt");
+        let flag = match self.userdata_at::<Arc<AtomicBool>>(LuaIndex::Stack(-1)) {
+            Ok(flag) => flag.clone(),
+            Err(_) => {
+                self.pop(1);
+                let flag = Arc::new(AtomicBool::new(false));
+                self.push_userdata(flag.clone());
+                self.push_value(LuaIndex::Stack(-1));
+                self.set_field(LuaIndex::Stack(-3), "interrupt");
+                let hook_flag = flag.clone();
+                let mask = HookMask {
+                    count: Some(INTERRUPT_CHECK_INTERVAL),
+                    ..HookMask::default()
+                };
+                self.add_hook(mask, move |state, _| {
+                    if hook_flag.swap(false, Ordering::SeqCst) {
+                        Err(RunError::interrupted(state.backtrace()))
+                    } else {
+                        Ok(())
+                    }
+                });
+                flag
+            }
+        };
+        self.pop(2);
+        InterruptHandle { flag: flag }
+    }
+}
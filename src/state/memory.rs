@@ -0,0 +1,210 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::ptr;
+use libc::{self, c_void, size_t};
+
+use ffi;
+use state::State;
+
+/// The number of objects of a kind created by Lua, and the bytes allocated for them when they were
+/// created. Memory allocated later, such as for growing tables, is not attributed to the object.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ObjectStats {
+    /// The number of objects created.
+    pub count: u64,
+    /// The bytes allocated when the objects were created.
+    pub bytes: u64,
+}
+
+/// Memory statistics of a state, returned by `State::memory_stats()`.
+///
+/// The statistics are collected by the allocator lowlua creates states with, and cover the state
+/// and all of its threads since it was created.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// The number of bytes currently allocated.
+    pub in_use: usize,
+    /// The highest number of bytes allocated at once since the state was created or
+    /// `reset_peak_memory()` was last called.
+    pub peak: usize,
+    /// The number of blocks allocated.
+    pub allocations: u64,
+    /// The number of blocks resized.
+    pub reallocations: u64,
+    /// The number of blocks freed.
+    pub frees: u64,
+    /// The number of allocations which failed.
+    pub failures: u64,
+    /// Strings created.
+    pub strings: ObjectStats,
+    /// Tables created.
+    pub tables: ObjectStats,
+    /// Lua and native closures created.
+    pub functions: ObjectStats,
+    /// Full userdata created, including those created with `push_userdata()`.
+    pub userdata: ObjectStats,
+    /// Threads created.
+    pub threads: ObjectStats,
+    /// Other allocations, such as function prototypes, upvalues, and the arrays of tables.
+    pub other: ObjectStats,
+}
+
+/// Memory used by userdata of a Rust type, returned by `State::userdata_memory()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserdataStats {
+    /// The name of the type.
+    pub type_name: &'static str,
+    /// The number of values of the type which are currently alive.
+    pub count: usize,
+    /// The bytes used by the values, not counting memory they own outside of Lua.
+    pub bytes: usize,
+}
+
+/// The allocator state, passed to `alloc` as its user data.
+pub struct Allocator {
+    stats: MemoryStats,
+    /// Userdata accounting by type, if enabled.
+    userdata: Option<HashMap<TypeId, UserdataStats>>,
+    /// Incremented whenever userdata accounting is enabled, so that values counted before it was
+    /// last disabled are not subtracted from the new counts. Never 0.
+    generation: u32,
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator {
+            stats: MemoryStats::default(),
+            userdata: None,
+            generation: 1,
+        }
+    }
+
+    /// Records that a userdata of `size` bytes was created. Returns the generation the value was
+    /// counted in, or 0 if accounting is disabled.
+    pub fn add_userdata(&mut self, type_id: TypeId, type_name: &'static str, size: usize) -> u32 {
+        match self.userdata {
+            Some(ref mut userdata) => {
+                let stats = userdata.entry(type_id).or_insert_with(|| {
+                    UserdataStats {
+                        type_name: type_name,
+                        count: 0,
+                        bytes: 0,
+                    }
+                });
+                stats.count += 1;
+                stats.bytes += size;
+                self.generation
+            }
+            None => 0,
+        }
+    }
+
+    /// Records that a userdata of `size` bytes, counted by `add_userdata()` in the given
+    /// generation, was destroyed.
+    pub fn remove_userdata(&mut self, type_id: TypeId, size: usize, generation: u32) {
+        if generation != self.generation {
+            return;
+        }
+        if let Some(ref mut userdata) = self.userdata {
+            let stats = userdata.get_mut(&type_id).unwrap();
+            stats.count -= 1;
+            stats.bytes -= size;
+        }
+    }
+}
+
+/// The allocation function of states created by lowlua.
+pub extern "C" fn alloc(ud: *mut c_void,
+                        ptr: *mut c_void,
+                        osize: size_t,
+                        nsize: size_t)
+                        -> *mut c_void {
+    let stats = unsafe { &mut (*(ud as *mut Allocator)).stats };
+    if nsize == 0 {
+        if !ptr.is_null() {
+            unsafe { libc::free(ptr) };
+            stats.in_use -= osize as usize;
+            stats.frees += 1;
+        }
+        return ptr::null_mut();
+    }
+    let new = unsafe { libc::realloc(ptr, nsize) };
+    if new.is_null() {
+        stats.failures += 1;
+        return new;
+    }
+    if ptr.is_null() {
+        // For new blocks, `osize` is the type of the object being created
+        stats.allocations += 1;
+        stats.in_use += nsize as usize;
+        let objects = match osize as i32 & 0xF {
+            ffi::LUA_TSTRING => &mut stats.strings,
+            ffi::LUA_TTABLE => &mut stats.tables,
+            ffi::LUA_TFUNCTION => &mut stats.functions,
+            ffi::LUA_TUSERDATA => &mut stats.userdata,
+            ffi::LUA_TTHREAD => &mut stats.threads,
+            _ => &mut stats.other,
+        };
+        objects.count += 1;
+        objects.bytes += nsize as u64;
+    } else {
+        stats.reallocations += 1;
+        stats.in_use = stats.in_use - osize as usize + nsize as usize;
+    }
+    if stats.in_use > stats.peak {
+        stats.peak = stats.in_use;
+    }
+    new
+}
+
+impl State {
+    /// Returns the memory statistics of this state, or `None` if the state was not created by
+    /// lowlua, as in `State::from_raw()`.
+    pub fn memory_stats(&self) -> Option<MemoryStats> {
+        self.allocator().map(|allocator| unsafe { (*allocator).stats })
+    }
+
+    /// Resets the peak memory usage to the current usage, so that the peak of a particular phase
+    /// can be measured.
+    pub fn reset_peak_memory(&mut self) {
+        if let Some(allocator) = self.allocator() {
+            unsafe { (*allocator).stats.peak = (*allocator).stats.in_use };
+        }
+    }
+
+    /// Enables or disables accounting of the memory used by userdata created with
+    /// `push_userdata()`, by Rust type. Accounting is disabled by default, as it adds a hash
+    /// table lookup to the creation and finalization of every userdata. Disabling it discards the
+    /// collected data.
+    pub fn set_userdata_accounting(&mut self, enabled: bool) {
+        if let Some(allocator) = self.allocator() {
+            unsafe {
+                if !enabled {
+                    (*allocator).userdata = None;
+                } else if (*allocator).userdata.is_none() {
+                    (*allocator).userdata = Some(HashMap::new());
+                    (*allocator).generation = (*allocator).generation.checked_add(1).unwrap_or(1);
+                }
+            }
+        }
+    }
+
+    /// Returns the memory used by userdata of each Rust type, largest first, if accounting was
+    /// enabled with `set_userdata_accounting()`. Only values created while accounting was enabled
+    /// are included.
+    pub fn userdata_memory(&self) -> Vec<UserdataStats> {
+        let mut result: Vec<UserdataStats> = match self.allocator() {
+            Some(allocator) => {
+                match unsafe { &(*allocator).userdata } {
+                    &Some(ref userdata) => {
+                        userdata.values().filter(|stats| stats.count > 0).cloned().collect()
+                    }
+                    &None => Vec::new(),
+                }
+            }
+            None => Vec::new(),
+        };
+        result.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.type_name.cmp(b.type_name)));
+        result
+    }
+}
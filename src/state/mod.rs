@@ -1,26 +1,88 @@
 mod traits;
-
-use std::{io, ptr};
+mod interrupt;
+mod builder;
+mod env;
+mod require;
+mod module;
+mod guard;
+mod debug;
+mod hook;
+mod profile;
+mod coverage;
+mod memory;
+mod gc;
+mod reference;
+mod intern;
+
+use std::{io, ptr, slice, process};
+use std::io::{BufRead, Write};
+use std::fs::File;
+use std::path::Path;
 use std::ffi::{CStr, CString};
 use std::mem;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use std::any::{Any, TypeId};
 use std::intrinsics::type_name;
 use std::panic::{self, AssertUnwindSafe};
-use libc::{self, c_int, c_char, size_t, c_void};
+use libc::{c_int, c_char, size_t, c_void};
 
 use ffi;
-use super::{LoadResult, LoadError, RunResult, RunError, LuaType, LuaOperator, LuaCallResults,
-            LuaIndex, LuaString, NativeFunction};
+use super::{Result, LoadResult, LoadError, RunResult, RunError, LuaType, LuaOperator,
+            LuaCompare, LuaCallResults, LuaIndex, LoadMode, SyntaxError, NativeFunction,
+            AbsIndex, RelIndex, StackSlot};
 pub use self::traits::*;
+pub use self::interrupt::InterruptHandle;
+pub use self::builder::{StateBuilder, StandardLib};
+pub use self::module::ModuleBuilder;
+pub use self::guard::StackGuard;
+pub use self::debug::{ActivationRecord, DebugInfo, UpvalueId};
+pub use self::hook::{HookEvent, HookMask, HookId};
+pub use self::profile::{Profiler, ProfileMode, Profile, FunctionStats};
+pub use self::coverage::{Coverage, CoverageCollector};
+pub use self::memory::{MemoryStats, ObjectStats, UserdataStats};
+pub use self::gc::GcConfig;
+pub use self::reference::{LuaRef, WeakRef, WeakMode};
+pub use self::intern::LuaString;
+
+/// The size of the pieces in which `State::load_stream()` reads its stream.
+const LOAD_CHUNK_SIZE: usize = 4096;
+
+/// Type for functions which are called when a userdata finalizer panics while the state is being
+/// closed (see `State::set_finalizer_panic_handler()`).
+pub type FinalizerPanicHandler = fn(&RunError);
 
 /// The userdata memory stored in Lua.
 struct Userdata<T: Any> {
     type_id: TypeId,
+    /// The userdata accounting generation the value was counted in, or 0 if it was not counted.
+    accounted: u32,
     value: T,
 }
 
+/// Keys of registry entries whose Rust handles were dropped. Handles have no access to the state,
+/// so they queue their key here, and the state removes the entries the next time it uses them.
+struct ReleaseQueue<K> {
+    keys: RefCell<Vec<K>>,
+}
+
+impl<K> ReleaseQueue<K> {
+    fn new() -> ReleaseQueue<K> {
+        ReleaseQueue { keys: RefCell::new(Vec::new()) }
+    }
+
+    fn release(&self, key: K) {
+        self.keys.borrow_mut().push(key);
+    }
+
+    /// Removes and returns the queued keys.
+    fn take(&self) -> Vec<K> {
+        mem::replace(&mut *self.keys.borrow_mut(), Vec::new())
+    }
+}
+
 /// Contains the Lua state.
 ///
 /// See the [module level documentation](index.html) for more details.
@@ -33,37 +95,44 @@ impl State {
     /// Creates a new Lua state. This function can panic if state creation fails, though this
     /// only happens in extreme scenarios such as insufficient memory.
     pub fn new() -> State {
-        // Create the Lua state through the FFI
-        let lua = unsafe { ffi::lua_newstate(alloc, ptr::null_mut()) };
-        extern "C" fn alloc(_ud: *mut c_void,
-                            ptr: *mut c_void,
-                            _osize: size_t,
-                            nsize: size_t)
-                            -> *mut c_void {
-            unsafe {
-                if nsize == 0 {
-                    libc::free(ptr as *mut c_void);
-                    ptr::null_mut()
-                } else {
-                    libc::realloc(ptr, nsize)
-                }
-            }
-        }
+        // Create the Lua state through the FFI. The allocator is freed when the state is closed.
+        let allocator = Box::into_raw(Box::new(memory::Allocator::new()));
+        let lua = unsafe { ffi::lua_newstate(memory::alloc, allocator as *mut c_void) };
 
         if lua.is_null() {
+            unsafe { drop(Box::from_raw(allocator)) };
             panic!("lua_newstate failed");
         }
 
-        // UNDONE: Set the panic handler
-        // Since panicking and unwinding the stack is undefined behavior, just let Lua abort for us
-        // instead of causing a mess.
-        // unsafe { ffi::lua_atpanic(lua, panic) };
-        // extern "C" fn panic(lua: *mut ffi::lua_State) -> c_int {
-        // let mut state = State::from_raw_state(lua);
-        // let err = state.to_string(LuaIndex::Stack(-1)).unwrap();
-        // panic!("PANIC: unprotected error in call to Lua API ({})", err);
-        // }
-
+        // Set the panic handler
+        // Since unwinding through Lua is undefined behavior, the process is aborted, but not
+        // before the error is reported.
+        unsafe { ffi::lua_atpanic(lua, at_panic) };
+        extern "C" fn at_panic(lua: *mut ffi::lua_State) -> c_int {
+            let message = {
+                let state = State::from_raw_state(lua);
+                if let Ok(err) = state.userdata_at::<RunError>(LuaIndex::Stack(-1)) {
+                    let mut message = err.message.clone();
+                    for line in &err.backtrace {
+                        message.push_str("\n\t");
+                        message.push_str(line);
+                    }
+                    message
+                } else if let Ok(err) = state.userdata_at::<Box<Any + Send>>(LuaIndex::Stack(-1)) {
+                    format!("panic: {}", panic_message(err))
+                } else if state.is_string(LuaIndex::Stack(-1)) {
+                    unsafe {
+                        CStr::from_ptr(ffi::lua_tostring(lua, -1)).to_string_lossy().into_owned()
+                    }
+                } else {
+                    format!("error object is a {:?} value", state.type_at(LuaIndex::Stack(-1)))
+                }
+            };
+            let _ = writeln!(io::stderr(),
+                             "PANIC: unprotected error in call to Lua API ({})",
+                             message);
+            process::abort();
+        }
 
         // Create the state object
         let mut state = State {
@@ -71,57 +140,34 @@ impl State {
             should_free: true,
         };
 
-        // Add the address of the State object to the "extradata" and create a table in the
-        // registry. The address isn't actually used to track the location of the object, but the
-        // usage of an address for the key of a registry table was recommended by the Lua 5.3
-        // reference manual.
-        //
-        // From here, populate our registry table with some important values:
-        // * `errfunc`: A function called to generate a backtrace on a Lua runtime error.
-        // * `string`: A table that maps internal string pointers to their corresponding string
-        //             values.
-        // * `mt`: A table that maps `TypeId` hashes to their corresponding userdata metatables.
-        // * `user`: A table reserved for external crate use returned by `get_registry()`.
-        unsafe {
-            let extraspace = ffi::lua_getextraspace(state.lua) as *mut *mut c_void;
-            *extraspace = (&mut state as *mut State) as *mut c_void;
-            ffi::lua_newtable(state.lua);
-            // errfunc
-            ffi::lua_pushcfunction(state.lua, errfunc);
-            ffi::lua_setfield(state.lua, -2, b"errfunc\0".as_ptr() as *const c_char);
-            // string
-            ffi::lua_newtable(state.lua);
-            ffi::lua_setfield(state.lua, -2, b"string\0".as_ptr() as *const c_char);
-            // mt
-            ffi::lua_newtable(state.lua);
-            ffi::lua_setfield(state.lua, -2, b"mt\0".as_ptr() as *const c_char);
-            // user
-            ffi::lua_newtable(state.lua);
-            ffi::lua_setfield(state.lua, -2, b"user\0".as_ptr() as *const c_char);
-            // save to registry
-            ffi::lua_rawsetp(state.lua, ffi::LUA_REGISTRYINDEX, *extraspace);
-        }
+        // Create our registry table up front
+        state.get_internal_registry();
+        state.pop(1);
+        state
+    }
 
-        extern "C" fn errfunc(lua: *mut ffi::lua_State) -> c_int {
-            // Coerce the error value into a RunError with a backtrace, unless it's a PanicError.
-            let mut state = State::from_raw_state(lua);
-            if state.is_userdata_of_type::<RunError>(LuaIndex::Stack(1)) ||
-               state.is_userdata_of_type::<Box<Any + Send>>(LuaIndex::Stack(1)) {
-                // do nothing
-            } else {
-                // Coerce to RunError
-                let message = match state.at::<String>(LuaIndex::Stack(1)) {
-                    Ok(val) => val,
-                    Err(_) => "unknown error".to_string(),
-                };
-                let bt = state.backtrace();
-                state.push_userdata(RunError::new(message, bt));
-            }
-            1
-        }
+    /// Wraps a Lua state which was not created by lowlua, such as one owned by a host application
+    /// written in C.
+    ///
+    /// The returned object never closes the underlying state; its owner remains responsible for
+    /// that. lowlua's internal registry table is created in the state if it doesn't exist yet, so
+    /// the same state may be wrapped any number of times.
+    ///
+    /// This function is unsafe because `lua` must point to a valid Lua 5.3 state which outlives the
+    /// returned object.
+    pub unsafe fn from_raw(lua: *mut ffi::lua_State) -> State {
+        let mut state = State::from_raw_state(lua);
+        state.get_internal_registry();
+        state.pop(1);
         state
     }
 
+    /// Returns the underlying `lua_State` pointer, for interoperating with other code using the
+    /// Lua C API. The pointer remains owned by this object (if it was created with `new()`).
+    pub fn as_raw_ptr(&self) -> *mut ffi::lua_State {
+        self.lua
+    }
+
     /// Opens all standard Lua libraries into the state.
     pub fn open_libs(&mut self) {
         unsafe { ffi::luaL_openlibs(self.lua) }
@@ -129,48 +175,150 @@ impl State {
 
     /// Load string containing Lua code as a Lua function on the top of the stack.
     /// If an error occurs, nothing is pushed to the stack.
-    pub fn load_string(&mut self, str: &str, chunkname: &str) -> LoadResult<()> {
-        // TODO: special case for strings so there's not so much memory movement?
-        let vec = str.as_bytes().to_vec();
-        self.load_stream(vec.as_slice(), chunkname)
+    pub fn load_string(&mut self, str: &str, chunkname: &str, mode: LoadMode) -> LoadResult<()> {
+        self.load_bytes(str.as_bytes(), chunkname, mode)
     }
 
-    /// Load string containing Lua code as a Lua function on the top of the stack.
+    /// Load a buffer containing a Lua chunk as a Lua function on the top of the stack. Unlike
+    /// `load_string()`, the buffer may contain a binary chunk produced by `dump()`.
+    /// If an error occurs, nothing is pushed to the stack. A chunk name containing a NUL byte
+    /// results in a `LoadError::Io`.
+    pub fn load_bytes(&mut self, bytes: &[u8], chunkname: &str, mode: LoadMode) -> LoadResult<()> {
+        let chunkname = try!(chunk_name(chunkname));
+        let result = unsafe {
+            ffi::luaL_loadbufferx(self.lua,
+                                  bytes.as_ptr() as *const c_char,
+                                  bytes.len() as size_t,
+                                  chunkname.as_ptr(),
+                                  mode.to_ffi())
+        };
+        self.lua_to_rust_load_result(result)
+    }
+
+    /// Load a stream containing a Lua chunk as a Lua function on the top of the stack.
     /// If an error occurs, nothing is pushed to the stack.
-    pub fn load_stream<R: io::Read>(&mut self, stream: R, chunkname: &str) -> LoadResult<()> {
+    ///
+    /// The stream is read in fixed-size pieces as Lua requests them, so the whole source is never
+    /// buffered in memory. If reading fails, or the chunk name contains a NUL byte, the error is
+    /// returned as a `LoadError::Io`.
+    ///
+    /// `mode` controls whether text chunks, binary chunks or both are accepted. Loading a chunk of
+    /// the wrong kind results in a `LoadError::Syntax`.
+    pub fn load_stream<R: io::Read>(&mut self,
+                                    stream: R,
+                                    chunkname: &str,
+                                    mode: LoadMode)
+                                    -> LoadResult<()> {
         extern "C" fn reader<R: io::Read>(_lua: *mut ffi::lua_State,
                                           data: *mut c_void,
                                           size: *mut size_t)
                                           -> *const c_char {
-            unsafe {
-                let rd = &mut *(data as *mut ReaderData<R>);
-                rd.string.truncate(0);
-                rd.stream.read_to_end(&mut rd.string).unwrap();
-                *size = rd.string.len() as size_t;
-                rd.string.as_ptr() as *const c_char
-            }
+            let rd = unsafe { &mut *(data as *mut ReaderData<R>) };
+            let result = {
+                let stream = &mut rd.stream;
+                let buf = &mut rd.buf;
+                panic::catch_unwind(AssertUnwindSafe(|| loop {
+                    match stream.read(buf) {
+                        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        result => return result,
+                    }
+                }))
+            };
+            // Returning a size of 0 signals the end of the chunk to Lua, so stop on errors too
+            let len = match result {
+                Ok(Ok(len)) => len,
+                Ok(Err(err)) => {
+                    rd.error = Some(err);
+                    0
+                }
+                Err(err) => {
+                    rd.panic = Some(err);
+                    0
+                }
+            };
+            unsafe { *size = len as size_t };
+            rd.buf.as_ptr() as *const c_char
         }
 
         struct ReaderData<R: io::Read> {
             stream: R,
-            string: Vec<u8>,
+            buf: Vec<u8>,
+            error: Option<io::Error>,
+            panic: Option<Box<Any + Send>>,
         }
 
+        let chunkname = try!(chunk_name(chunkname));
         let mut data = ReaderData {
             stream: stream,
-            string: Vec::new(),
+            buf: vec![0; LOAD_CHUNK_SIZE],
+            error: None,
+            panic: None,
         };
 
         let result = unsafe {
             ffi::lua_load(self.lua,
                           reader::<R>,
                           (&mut data as *mut ReaderData<R>) as *mut c_void,
-                          CString::new(chunkname).unwrap().as_ptr(),
-                          ptr::null())
+                          chunkname.as_ptr(),
+                          mode.to_ffi())
         };
+        if data.error.is_some() || data.panic.is_some() {
+            // Lua saw a truncated chunk, so discard whatever it made of it. `lua_load()` always
+            // pushes either the function or an error message.
+            self.pop(1);
+            if let Some(err) = data.panic.take() {
+                panic::resume_unwind(err);
+            }
+            return Err(LoadError::Io(data.error.take().unwrap()));
+        }
         self.lua_to_rust_load_result(result)
     }
 
+    /// Load a file containing a Lua chunk as a Lua function on the top of the stack.
+    /// If an error occurs, nothing is pushed to the stack.
+    ///
+    /// The chunk name is `@path`, so Lua reports errors and backtraces with the file name. As with
+    /// the stand-alone interpreter, a leading UTF-8 byte order mark and a first line starting with
+    /// `#` (such as a Unix shebang) are skipped. Line numbers are unaffected by this.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P, mode: LoadMode) -> LoadResult<()> {
+        let path = path.as_ref();
+        let mut reader = io::BufReader::new(try!(File::open(path)));
+        try!(skip_file_prefix(&mut reader));
+        self.load_stream(reader, &format!("@{}", path.display()), mode)
+    }
+
+    /// Dumps the Lua function at the top of the stack as a binary chunk, which can be loaded again
+    /// with `load_bytes()` and `LoadMode::Binary`. If `strip` is `true`, debug information such
+    /// as line numbers and local variable names is omitted to save space. The function is not
+    /// popped.
+    ///
+    /// Returns an empty vector if the value at the top of the stack is not a Lua function.
+    pub fn dump(&mut self, strip: bool) -> Vec<u8> {
+        extern "C" fn writer(_lua: *mut ffi::lua_State,
+                             p: *const c_void,
+                             size: size_t,
+                             data: *mut c_void)
+                             -> c_int {
+            unsafe {
+                let buf = &mut *(data as *mut Vec<u8>);
+                buf.extend_from_slice(slice::from_raw_parts(p as *const u8, size as usize));
+            }
+            0
+        }
+
+        let mut buf = Vec::new();
+        let result = unsafe {
+            ffi::lua_dump(self.lua,
+                          writer,
+                          (&mut buf as *mut Vec<u8>) as *mut c_void,
+                          if strip { 1 } else { 0 })
+        };
+        if result != 0 {
+            buf.clear();
+        }
+        buf
+    }
+
     /// Calls a function.
     ///
     /// To call a function you must use the following protocol: first, the function to be called is
@@ -191,15 +339,98 @@ impl State {
         };
         self.get_internal_registry();
         self.get_field(LuaIndex::Stack(-1), "errfunc");
-        self.remove(-2);
-        let errfunc_idx = self.abs_index(LuaIndex::Stack(-(nargs as i32) - 2)).to_stack();
+        self.remove(RelIndex(-2));
+        let errfunc_idx = self.abs_slot(RelIndex(-(nargs as i32) - 2)).unwrap();
         self.insert(errfunc_idx);
-        let result =
-            unsafe { ffi::lua_pcall(self.lua, nargs as c_int, nresults, errfunc_idx as c_int) };
+        let result = unsafe {
+            ffi::lua_pcall(self.lua, nargs as c_int, nresults, errfunc_idx.get() as c_int)
+        };
         self.remove(errfunc_idx);
         self.lua_to_rust_run_result(result)
     }
 
+    /// Loads and runs a string containing Lua code, discarding any results. The chunk is named
+    /// `(eval)` in errors and backtraces. Binary chunks are not accepted.
+    pub fn exec(&mut self, source: &str) -> Result<()> {
+        self.eval(source)
+    }
+
+    /// Loads and runs a string containing Lua code, converting its results to `R`. The chunk is
+    /// named `(eval)` in errors and backtraces. Binary chunks are not accepted.
+    pub fn eval<R: FromLuaMulti>(&mut self, source: &str) -> Result<R> {
+        try!(self.load_string(source, "=(eval)", LoadMode::Text));
+        Ok(try!(self.call_function_on_top()))
+    }
+
+    /// Loads and runs a file containing Lua code (see `load_file()`), converting its results to
+    /// `R`. Binary chunks are not accepted.
+    pub fn do_file<R: FromLuaMulti, P: AsRef<Path>>(&mut self, path: P) -> Result<R> {
+        try!(self.load_file(path, LoadMode::Text));
+        Ok(try!(self.call_function_on_top()))
+    }
+
+    /// Runs `f` inside a protected call, so that Lua errors raised by the stack operations it
+    /// performs are returned as a `RunError` instead of aborting the process.
+    ///
+    /// Many operations, such as `get_table()`, `set_field()`, `concat()`, `len()` and `arith()`,
+    /// may call metamethods which raise errors. Outside of a function called by Lua, such errors
+    /// are unprotected and abort the process, so they should be wrapped with this function when
+    /// operating on untrusted values.
+    ///
+    /// `f` runs in a new stack frame, like a native function: the `nargs` values at the top of the
+    /// stack are popped and become its stack, starting at index 1. When `f` returns successfully,
+    /// the values it left on its stack are pushed back onto this stack, adjusted to `results` as
+    /// with `call()`. If `f` fails, nothing is pushed.
+    pub fn protect<F, R>(&mut self, nargs: u32, results: LuaCallResults, f: F) -> RunResult<R>
+        where F: FnOnce(&mut State) -> RunResult<R>
+    {
+        extern "C" fn func<F, R>(lua: *mut ffi::lua_State) -> c_int
+            where F: FnOnce(&mut State) -> RunResult<R>
+        {
+            raise_after_drop(lua, |state| {
+                let data = unsafe {
+                    &mut *(ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) as
+                           *mut ProtectData<F, R>)
+                };
+                let f = data.f.take().unwrap();
+                // Call function and catch panics
+                match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *state))) {
+                    Ok(Ok(val)) => {
+                        data.result = Some(val);
+                        Ok(state.get_top() as c_int)
+                    }
+                    Ok(Err(err)) => {
+                        state.push_userdata(err);
+                        Err(())
+                    }
+                    Err(err) => {
+                        state.push_userdata(err);
+                        Err(())
+                    }
+                }
+            })
+        }
+
+        struct ProtectData<F, R> {
+            f: Option<F>,
+            result: Option<R>,
+        }
+
+        let mut data = ProtectData {
+            f: Some(f),
+            result: None,
+        };
+        self.ensure_stack(2);
+        unsafe {
+            ffi::lua_pushlightuserdata(self.lua,
+                                       (&mut data as *mut ProtectData<F, R>) as *mut c_void);
+            ffi::lua_pushcclosure(self.lua, func::<F, R>, 1);
+        }
+        self.insert(RelIndex(-(nargs as i32) - 1));
+        try!(self.call(nargs, results));
+        Ok(data.result.take().unwrap())
+    }
+
     /// Push a type on the top of the stack.
     pub fn push<T: ToLua>(&mut self, val: T) {
         val.to_lua(self);
@@ -230,45 +461,17 @@ impl State {
     /// The maximum value for n is 254. This differs from standard C Lua, as lowlua needs the first
     /// index internally.
     pub fn push_closure(&mut self, f: NativeFunction, n: u32) {
-        extern "C" fn func(lua: *mut ffi::lua_State) -> c_int {
-            unsafe {
-                let f =
-                    &*(ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) as *mut NativeFunction);
-                let mut state = State::from_raw_state(lua);
-                // Call function and catch panics
-                let panic_result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut state)));
-                match panic_result {
-                    // No panic
-                    Ok(result) => {
-                        match result {
-                            Ok(val) => val as c_int,
-                            Err(err) => {
-                                state.push_userdata(err);
-                                ffi::lua_error(state.lua);
-                                0 // unreachable
-                            }
-                        }
-                    }
-                    // Panic!
-                    Err(err) => {
-                        state.push_userdata(err);
-                        ffi::lua_error(state.lua);
-                        0 // unreachable
-                    }
-                }
-            }
-        }
-
         unsafe {
+            self.ensure_stack(1);
             // Push userdata instead of light userdata, as some platforms may have differing
             // pointer sizes between functions and variables.
             let ud = ffi::lua_newuserdata(self.lua, mem::size_of::<NativeFunction>()) as *mut NativeFunction;
             *ud = f;
             let n = (n + 1) as i32;
             if n > 1 {
-                self.insert(-n);
+                self.insert(RelIndex(-n));
             }
-            ffi::lua_pushcclosure(self.lua, func, n);
+            ffi::lua_pushcclosure(self.lua, call_native, n);
         }
     }
 
@@ -278,15 +481,19 @@ impl State {
     /// This type can be later accessed by `userdata_at()` with safe type-checking, as lowlua
     /// uses `std::any` internally to keep track of userdata types.
     pub fn push_userdata<T: Any>(&mut self, value: T) {
+        // The userdata, the internal registry and the metatable table are pushed at once
+        self.ensure_stack(3);
         unsafe {
             // Push to stack
             let ud = Userdata {
                 type_id: TypeId::of::<T>(),
+                accounted: 0,
                 value: value,
             };
             let ptr =
                 ffi::lua_newuserdata(self.lua, mem::size_of::<Userdata<T>>()) as *mut Userdata<T>;
             ptr::write(ptr, ud);
+            (*ptr).accounted = self.add_userdata_stats::<T>();
 
             // Associate metatable
             self.get_metatable_of::<T>();
@@ -296,6 +503,7 @@ impl State {
 
     /// Pushes a `nil` value onto the stack.
     pub fn push_nil(&mut self) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushnil(self.lua) }
     }
 
@@ -344,6 +552,8 @@ impl State {
             let lua_obj = try!(self.userdata_at::<T>(idx));
             let mut obj = mem::uninitialized::<T>();
             ptr::copy_nonoverlapping(lua_obj as *const T, &mut obj as *mut T, 1);
+            let ud = ffi::lua_touserdata(self.lua, idx.to_ffi()) as *mut Userdata<T>;
+            self.remove_userdata_stats::<T>((*ud).accounted);
             Ok(obj)
         }
     }
@@ -375,6 +585,7 @@ impl State {
 
     /// Pushes a copy of the element at the given index onto the stack.
     pub fn push_value(&mut self, idx: LuaIndex) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushvalue(self.lua, idx.to_ffi()) }
     }
 
@@ -383,14 +594,26 @@ impl State {
     /// `-n` positions in the direction of the bottom, for a negative `n`. The absolute value of `n`
     /// must not be greater than the size of the slice being rotated. This function cannot be called
     /// with a pseudo-index, because a pseudo-index is not an actual stack position.
-    pub fn rotate(&mut self, idx: i32, n: i32) {
-        unsafe { ffi::lua_rotate(self.lua, idx as c_int, n as c_int) }
+    ///
+    /// Panics if the index or `n` is not valid.
+    pub fn rotate<S: StackSlot>(&mut self, idx: S, n: i32) {
+        assert!(self.check_rotate(idx, n).is_ok(),
+                "invalid rotation of {} at stack index {}",
+                n,
+                idx.slot());
+        unsafe { ffi::lua_rotate(self.lua, idx.slot() as c_int, n as c_int) }
     }
 
     /// Copies the element at index `fromidx` into the valid index `toidx`, replacing the value at
     /// that position. Values at other positions are not affected.
-    pub fn copy(&mut self, fromidx: i32, toidx: i32) {
-        unsafe { ffi::lua_copy(self.lua, fromidx as c_int, toidx as c_int) }
+    ///
+    /// Panics if either index is not valid.
+    pub fn copy<S: StackSlot, T: StackSlot>(&mut self, fromidx: S, toidx: T) {
+        assert!(self.is_valid_slot(fromidx) && self.is_valid_slot(toidx),
+                "invalid stack index {} or {}",
+                fromidx.slot(),
+                toidx.slot());
+        unsafe { ffi::lua_copy(self.lua, fromidx.slot() as c_int, toidx.slot() as c_int) }
     }
 
     /// Pop `n` elements from the stack.
@@ -401,21 +624,86 @@ impl State {
     /// Moves the top element into the given valid index, shifting up the elements above this index
     /// to open space. This function cannot be called with a pseudo-index, because a pseudo-index
     /// is not an actual stack position.
-    pub fn insert(&mut self, idx: i32) {
-        unsafe { ffi::lua_insert(self.lua, idx as c_int) }
+    ///
+    /// Panics if the index is not valid.
+    pub fn insert<S: StackSlot>(&mut self, idx: S) {
+        assert!(self.is_valid_slot(idx), "invalid stack index {}", idx.slot());
+        unsafe { ffi::lua_insert(self.lua, idx.slot() as c_int) }
     }
 
     /// Removes the element at the given valid index, shifting down the elements above this index to
     /// fill the gap. This function cannot be called with a pseudo-index, because a pseudo-index is
     /// not an actual stack position.
-    pub fn remove(&mut self, idx: i32) {
-        unsafe { ffi::lua_remove(self.lua, idx as c_int) }
+    ///
+    /// Panics if the index is not valid.
+    pub fn remove<S: StackSlot>(&mut self, idx: S) {
+        assert!(self.is_valid_slot(idx), "invalid stack index {}", idx.slot());
+        unsafe { ffi::lua_remove(self.lua, idx.slot() as c_int) }
     }
 
     /// Moves the top element into the given valid index without shifting any element (therefore
     /// replacing the value at that given index), and then pops the top element.
-    pub fn replace(&mut self, idx: i32) {
-        unsafe { ffi::lua_replace(self.lua, idx as c_int) }
+    ///
+    /// Panics if the index is not valid.
+    pub fn replace<S: StackSlot>(&mut self, idx: S) {
+        assert!(self.is_valid_slot(idx), "invalid stack index {}", idx.slot());
+        unsafe { ffi::lua_replace(self.lua, idx.slot() as c_int) }
+    }
+
+    /// Like `rotate()`, but returns an error instead of panicking if the index or `n` is not
+    /// valid.
+    pub fn try_rotate<S: StackSlot>(&mut self, idx: S, n: i32) -> RunResult<()> {
+        try!(self.check_rotate(idx, n));
+        self.rotate(idx, n);
+        Ok(())
+    }
+
+    /// Like `copy()`, but returns an error instead of panicking if either index is not valid.
+    pub fn try_copy<S: StackSlot, T: StackSlot>(&mut self, fromidx: S, toidx: T) -> RunResult<()> {
+        try!(self.check_slot(fromidx));
+        try!(self.check_slot(toidx));
+        self.copy(fromidx, toidx);
+        Ok(())
+    }
+
+    /// Like `insert()`, but returns an error instead of panicking if the index is not valid.
+    pub fn try_insert<S: StackSlot>(&mut self, idx: S) -> RunResult<()> {
+        try!(self.check_slot(idx));
+        self.insert(idx);
+        Ok(())
+    }
+
+    /// Like `remove()`, but returns an error instead of panicking if the index is not valid.
+    pub fn try_remove<S: StackSlot>(&mut self, idx: S) -> RunResult<()> {
+        try!(self.check_slot(idx));
+        self.remove(idx);
+        Ok(())
+    }
+
+    /// Like `replace()`, but returns an error instead of panicking if the index is not valid.
+    pub fn try_replace<S: StackSlot>(&mut self, idx: S) -> RunResult<()> {
+        try!(self.check_slot(idx));
+        self.replace(idx);
+        Ok(())
+    }
+
+    /// Returns `true` if the index refers to a value which is currently on the stack.
+    pub fn is_valid_slot<S: StackSlot>(&self, idx: S) -> bool {
+        self.abs_slot(idx).is_some()
+    }
+
+    /// Converts a stack index into the equivalent absolute index, or returns `None` if the index
+    /// does not refer to a value which is currently on the stack.
+    pub fn abs_slot<S: StackSlot>(&self, idx: S) -> Option<AbsIndex> {
+        let top = self.get_top();
+        let idx = idx.slot();
+        if idx > 0 && idx <= top {
+            AbsIndex::new(idx)
+        } else if idx < 0 && -idx <= top {
+            AbsIndex::new(top + idx + 1)
+        } else {
+            None
+        }
     }
 
     /// Ensures that the stack has space for at least `n` extra slots (that is, that you can safely
@@ -538,12 +826,70 @@ impl State {
         unsafe { ffi::lua_rawequal(self.lua, idx1.to_ffi(), idx2.to_ffi()) != 0 }
     }
 
+    /// Performs an arithmetic or bitwise operation over the two values (or one, in the case of
+    /// negations) at the top of the stack like `arith()`, pops the result and converts it to `R`.
+    ///
+    /// Unlike `arith()`, errors raised by the operation are returned instead of aborting.
+    pub fn arith_to<R: FromLua>(&mut self, op: LuaOperator) -> RunResult<R> {
+        let nargs = if op.is_unary() { 1 } else { 2 };
+        try!(self.protect(nargs, LuaCallResults::Num(1), move |state| {
+            state.arith(op);
+            Ok(())
+        }));
+        let result = self.at(LuaIndex::Stack(-1));
+        self.pop(1);
+        result
+    }
+
+    /// Performs an arithmetic or bitwise operation over `a` and `b` (or only `a`, in the case of
+    /// negations, where `b` is ignored), following the semantics of the corresponding Lua
+    /// operator, and converts the result to `R`. The stack is left unchanged.
+    pub fn arith_values<A: ToLua, B: ToLua, R: FromLua>(&mut self,
+                                                        op: LuaOperator,
+                                                        a: A,
+                                                        b: B)
+                                                        -> RunResult<R> {
+        self.push(a);
+        if !op.is_unary() {
+            self.push(b);
+        }
+        self.arith_to(op)
+    }
+
     /// Compares two Lua values. Returns `true` if the value at index `idx1` satisfies `op`
     /// when compared with the value at index `idx2`, following the semantics of the corresponding
     /// Lua operator (that is, it may call metamethods). Otherwise returns `false`.
     /// Also returns `false` if any of the indices is not valid.
-    pub fn compare(&mut self, idx1: LuaIndex, idx2: LuaIndex, op: LuaOperator) -> bool {
-        unsafe { ffi::lua_compare(self.lua, idx1.to_ffi(), idx2.to_ffi(), rust_to_lua_op(op)) != 0 }
+    ///
+    /// The comparison runs in a protected call, so comparing values which can't be ordered or a
+    /// metamethod raising an error results in an `Err`.
+    pub fn compare(&mut self, idx1: LuaIndex, idx2: LuaIndex, op: LuaCompare) -> RunResult<bool> {
+        if self.type_at(idx1).is_none() || self.type_at(idx2).is_none() {
+            return Ok(false);
+        }
+        let idx1 = self.abs_index(idx1);
+        let idx2 = self.abs_index(idx2);
+        self.push_value(idx1);
+        self.push_value(idx2);
+        self.protect(2, LuaCallResults::Num(0), move |state| {
+            Ok(unsafe { ffi::lua_compare(state.lua, 1, 2, rust_to_lua_compare(op)) != 0 })
+        })
+    }
+
+    /// Determines the ordering of the values at indices `idx1` and `idx2`, following the
+    /// semantics of the Lua `==` and `<` operators (that is, it may call metamethods). Returns
+    /// `None` if the values are neither equal nor ordered, such as when comparing with NaN, if
+    /// the comparison raises an error, or if any of the indices is not valid.
+    pub fn partial_cmp(&mut self, idx1: LuaIndex, idx2: LuaIndex) -> Option<Ordering> {
+        if let Ok(true) = self.compare(idx1, idx2, LuaCompare::Eq) {
+            Some(Ordering::Equal)
+        } else if let Ok(true) = self.compare(idx1, idx2, LuaCompare::Lt) {
+            Some(Ordering::Less)
+        } else if let Ok(true) = self.compare(idx2, idx1, LuaCompare::Lt) {
+            Some(Ordering::Greater)
+        } else {
+            None
+        }
     }
 
     /// Pushes onto the stack the value of the global `name`. Returns the `LuaType` of that value.
@@ -635,11 +981,20 @@ impl State {
     /// onto the top of the stack. This can be used to extend the functionality of a userdata type.
     pub fn get_metatable_of<T: Any>(&mut self) {
         extern "C" fn gc<T: Any>(lua: *mut ffi::lua_State) -> c_int {
-            unsafe {
-                let ptr = ffi::lua_touserdata(lua, 1) as *mut Userdata<T>;
-                ptr::drop_in_place(ptr);
-                0
-            }
+            // Unwinding into Lua is undefined behavior, so panics must be caught here
+            raise_after_drop(lua, |state| {
+                let ptr = unsafe { ffi::lua_touserdata(state.lua, 1) as *mut Userdata<T> };
+                state.remove_userdata_stats::<T>(unsafe { (*ptr).accounted });
+                match panic::catch_unwind(AssertUnwindSafe(|| unsafe { ptr::drop_in_place(ptr) })) {
+                    Ok(()) => Ok(0),
+                    Err(err) => {
+                        let message = format!("panic in finalizer of `{}`: {}",
+                                              unsafe { type_name::<T>() },
+                                              panic_message(&err));
+                        if state.finalizer_failed(message) { Err(()) } else { Ok(0) }
+                    }
+                }
+            })
         }
         // First, get a hash of the type, which is used to look up the appropriate metatable
         let mut hasher = DefaultHasher::new();
@@ -676,7 +1031,7 @@ impl State {
     pub fn get_registry(&mut self) {
         self.get_internal_registry();
         self.get_field(LuaIndex::Stack(-1), "user");
-        self.remove(-2);
+        self.remove(RelIndex(-2));
     }
 
     /// Pops a value from the stack and sets it as the new value of global `name`.
@@ -757,6 +1112,25 @@ impl State {
         self.set_global(name);
     }
 
+    /// Sets the function which is called when a userdata finalizer panics while the state is
+    /// being closed. By default, the error is printed to the standard error stream.
+    ///
+    /// Panics in finalizers which run at any other time are raised as Lua errors with the kind
+    /// `RunErrorKind::Finalizer`, which are returned by whichever protected operation triggered
+    /// the collection, such as `call()` or `gc_collect()`. If the collection was triggered by an
+    /// unprotected operation, the error is reported by the panic handler and the process aborts.
+    pub fn set_finalizer_panic_handler(&mut self, handler: FinalizerPanicHandler) {
+        self.get_internal_registry();
+        unsafe {
+            // Stored without a metatable, so that the handler is never finalized itself
+            let ud = ffi::lua_newuserdata(self.lua, mem::size_of::<FinalizerPanicHandler>()) as
+                     *mut FinalizerPanicHandler;
+            ptr::write(ud, handler);
+        }
+        self.set_field(LuaIndex::Stack(-2), "gcpanic");
+        self.pop(1);
+    }
+
     /// Stops the garbage collector.
     pub fn gc_stop(&mut self) {
         unsafe { ffi::lua_gc(self.lua, ffi::LUA_GCSTOP, 0) };
@@ -767,9 +1141,12 @@ impl State {
         unsafe { ffi::lua_gc(self.lua, ffi::LUA_GCRESTART, 0) };
     }
 
-    /// Performs a full garbage-collection cycle.
-    pub fn gc_collect(&mut self) {
-        unsafe { ffi::lua_gc(self.lua, ffi::LUA_GCCOLLECT, 0) };
+    /// Performs a full garbage-collection cycle. Returns an error if a finalizer fails.
+    pub fn gc_collect(&mut self) -> RunResult<()> {
+        self.protect(0, LuaCallResults::Num(0), |state| {
+            unsafe { ffi::lua_gc(state.lua, ffi::LUA_GCCOLLECT, 0) };
+            Ok(())
+        })
     }
 
     /// Returns the current amount of memory (in bytes) in use by Lua.
@@ -778,9 +1155,14 @@ impl State {
         size + unsafe { ffi::lua_gc(self.lua, ffi::LUA_GCCOUNTB, 0) as usize }
     }
 
-    /// Performs an incremental step of garbage collection.
-    pub fn gc_step(&mut self) {
-        unsafe { ffi::lua_gc(self.lua, ffi::LUA_GCSTEP, 0) };
+    /// Performs an incremental step of garbage collection, doing the amount of work needed to
+    /// collect `kb` kilobytes, or a single basic step if `kb` is 0. This works even if the
+    /// collector is stopped. Returns `true` if the step finished a collection cycle, or an error
+    /// if a finalizer fails.
+    pub fn gc_step(&mut self, kb: u32) -> RunResult<bool> {
+        self.protect(0, LuaCallResults::Num(0), |state| {
+            Ok(unsafe { ffi::lua_gc(state.lua, ffi::LUA_GCSTEP, kb as c_int) } != 0)
+        })
     }
 
     /// Sets `pause` as the new value for the "pause" of the collector
@@ -841,20 +1223,6 @@ impl State {
         unsafe { ffi::lua_stringtonumber(self.lua, CString::new(s).unwrap().as_ptr()) != 0 }
     }
 
-    /// Creates a LuaString from the passed value. This value is interned and stored in the registry
-    /// indefinitely.
-    pub fn intern(&mut self, s: &str) -> LuaString {
-        self.get_internal_registry();
-        self.get_field(LuaIndex::Stack(-1), "string");
-        self.push_string(s);
-        let val = self.to_string_ptr(LuaIndex::Stack(-1)).unwrap() as usize;
-        self.push_unsigned(val as u64);
-        self.insert(-2);
-        self.raw_set(LuaIndex::Stack(-3));
-        self.pop(2);
-        LuaString(val)
-    }
-
     /// Generates a backtrace.
     pub fn backtrace(&self) -> Vec<String> {
         unsafe {
@@ -903,33 +1271,233 @@ impl State {
     }
 
     // Misc
+    /// Calls the function on the top of the stack without arguments and converts its results,
+    /// leaving the stack as it was before the function was pushed.
+    fn call_function_on_top<R: FromLuaMulti>(&mut self) -> RunResult<R> {
+        let base = self.get_top() - 1;
+        try!(self.call(0, LuaCallResults::MultRet));
+        let n = self.get_top() - base;
+        let result = R::from_lua_multi(self, base + 1, n);
+        self.set_top(base);
+        result
+    }
+
     /// Push the internal registry onto the stack. This table is not exposed to external crates.
-    fn get_internal_registry(&mut self) {
+    ///
+    /// The table is created on first use, as the state may not have been created by lowlua.
+    pub(crate) fn get_internal_registry(&mut self) {
         unsafe {
-            let extraspace = ffi::lua_getextraspace(self.lua) as *const *mut c_void;
-            ffi::lua_rawgetp(self.lua, ffi::LUA_REGISTRYINDEX, *extraspace);
+            let key = &REGISTRY_KEY as *const u8 as *const c_void;
+            if ffi::lua_rawgetp(self.lua, ffi::LUA_REGISTRYINDEX, key) == ffi::LUA_TTABLE {
+                return;
+            }
+            ffi::lua_pop(self.lua, 1);
+            // Populate our registry table with some important values:
+            // * `errfunc`: A function called to generate a backtrace on a Lua runtime error.
+            // * `string`: A table that maps internal string pointers to their corresponding string
+            //             values.
+            // * `mt`: A table that maps `TypeId` hashes to their corresponding userdata metatables.
+            // * `user`: A table reserved for external crate use returned by `get_registry()`.
+            //
+            // Other values are only created when they are needed:
+            // * `interrupt`: The flag shared with the handles returned by `interrupt_handle()`.
+            // * `modules`: The modules registered from Rust, see `get_module_registry()`.
+            // * `gcpanic`: The handler set by `set_finalizer_panic_handler()`.
+            // * `closing`: `true` while the state is being closed.
+            // * `hooks`: The hooks added by `add_hook()`.
+            // * `refs`: The values of `LuaRef`s and `WeakRef`s, see `get_refs()`.
+            // * `interner`: The reference counts of the strings in `string`, see `get_interner()`.
+            // * `strict`: `true` if `set_strict_native_results()` enabled strict checking.
+            // * `variables`: The tables a stopped debug session refers to by variable reference,
+            //                see `dap::push_handles()`.
+            ffi::lua_newtable(self.lua);
+            // errfunc
+            ffi::lua_pushcfunction(self.lua, errfunc);
+            ffi::lua_setfield(self.lua, -2, b"errfunc\0".as_ptr() as *const c_char);
+            // string
+            ffi::lua_newtable(self.lua);
+            ffi::lua_setfield(self.lua, -2, b"string\0".as_ptr() as *const c_char);
+            // mt
+            ffi::lua_newtable(self.lua);
+            ffi::lua_setfield(self.lua, -2, b"mt\0".as_ptr() as *const c_char);
+            // user
+            ffi::lua_newtable(self.lua);
+            ffi::lua_setfield(self.lua, -2, b"user\0".as_ptr() as *const c_char);
+            // save to registry
+            ffi::lua_pushvalue(self.lua, -1);
+            ffi::lua_rawsetp(self.lua, ffi::LUA_REGISTRYINDEX, key);
         }
     }
 
+    /// Handles a panic in a userdata finalizer. While the state is being closed, Lua ignores
+    /// errors in finalizers, so the error is passed to the finalizer panic handler and `false` is
+    /// returned. Otherwise, the message is pushed and `true` is returned, and the caller should
+    /// raise it as an error.
+    fn finalizer_failed(&mut self, message: String) -> bool {
+        self.get_internal_registry();
+        let closing = self.get_field(LuaIndex::Stack(-1), "closing") == LuaType::Boolean;
+        self.pop(1);
+        if !closing {
+            self.pop(1);
+            self.push_string(&message);
+            return true;
+        }
+        let handler = if self.get_field(LuaIndex::Stack(-1), "gcpanic") == LuaType::Userdata {
+            Some(unsafe {
+                *(ffi::lua_touserdata(self.lua, -1) as *const FinalizerPanicHandler)
+            })
+        } else {
+            None
+        };
+        self.pop(2);
+        let err = RunError::finalizer(message);
+        match handler {
+            Some(handler) => handler(&err),
+            None => {
+                let _ = writeln!(io::stderr(), "lowlua: {}", err);
+            }
+        }
+        false
+    }
+
+    /// Returns an error if the index does not refer to a value on the stack.
+    fn check_slot<S: StackSlot>(&self, idx: S) -> RunResult<()> {
+        if self.is_valid_slot(idx) {
+            Ok(())
+        } else {
+            Err(RunError::new(format!("invalid stack index {} (stack size is {})",
+                                      idx.slot(),
+                                      self.get_top()),
+                              self.backtrace()))
+        }
+    }
+
+    /// Returns an error if `rotate(idx, n)` would be invalid.
+    fn check_rotate<S: StackSlot>(&self, idx: S, n: i32) -> RunResult<()> {
+        let abs = match self.abs_slot(idx) {
+            Some(abs) => abs.get(),
+            None => return self.check_slot(idx),
+        };
+        let size = self.get_top() - abs + 1;
+        if n.abs() > size {
+            Err(RunError::new(format!("cannot rotate {} values by {} positions", size, n),
+                              self.backtrace()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the allocator of this state, or `None` if the state was created outside of lowlua
+    /// with a different allocator.
+    ///
+    /// The allocator is also accessed by Lua's allocation function, so no reference to it may
+    /// outlive a single statement.
+    fn allocator(&self) -> Option<*mut memory::Allocator> {
+        unsafe {
+            let mut ud = ptr::null_mut();
+            let f = ffi::lua_getallocf(self.lua, &mut ud);
+            if f as usize == memory::alloc as usize && !ud.is_null() {
+                Some(ud as *mut memory::Allocator)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Counts a new userdata of type `T`, if accounting is enabled. Returns the value to store in
+    /// `Userdata::accounted`.
+    fn add_userdata_stats<T: Any>(&self) -> u32 {
+        match self.allocator() {
+            Some(allocator) => unsafe {
+                (*allocator).add_userdata(TypeId::of::<T>(),
+                                          type_name::<T>(),
+                                          mem::size_of::<Userdata<T>>())
+            },
+            None => 0,
+        }
+    }
+
+    /// Removes a destroyed userdata of type `T` from the accounting, if it was counted.
+    fn remove_userdata_stats<T: Any>(&self, accounted: u32) {
+        if accounted == 0 {
+            return;
+        }
+        if let Some(allocator) = self.allocator() {
+            unsafe {
+                (*allocator).remove_userdata(TypeId::of::<T>(),
+                                             mem::size_of::<Userdata<T>>(),
+                                             accounted)
+            };
+        }
+    }
+
+    /// Ensures that `n` values can be pushed onto the stack, panicking otherwise. Lua only
+    /// guarantees a small number of free slots, and overflowing the stack corrupts memory.
+    fn ensure_stack(&self, n: i32) {
+        if !self.check_stack(n) {
+            panic!("Lua stack overflow");
+        }
+    }
+
+    /// Verifies that a native function returning `nresults` results follows the protocol
+    /// described in `NativeFunction`. Only used when debug assertions are enabled.
+    fn check_native_results(&mut self, nargs: i32, nresults: u32) -> RunResult<u32> {
+        let top = self.get_top();
+        if nresults as i64 > top as i64 {
+            return Err(RunError::new(format!("native function returned {} results, but only {} \
+                                              values are on the stack",
+                                             nresults,
+                                             top),
+                                     self.backtrace()));
+        }
+        // Lua discards the values below the results, so anything other than the arguments there
+        // is legal, but may be a leak. Only the number of values is known, so arguments which
+        // were popped hide the same number of leftover values.
+        let junk = top - nresults as i32 - nargs;
+        if junk > 0 {
+            self.ensure_stack(2);
+            self.get_internal_registry();
+            let strict = self.get_field(LuaIndex::Stack(-1), "strict") == LuaType::Boolean;
+            self.pop(2);
+            if strict {
+                return Err(RunError::new(format!("native function left {} extra values below its \
+                                                  results",
+                                                 junk),
+                                         self.backtrace()));
+            }
+        }
+        Ok(nresults)
+    }
+
     // Push
 
     fn push_number(&mut self, n: f64) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushnumber(self.lua, n as ffi::lua_Number) }
     }
 
     fn push_integer(&mut self, n: i64) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushinteger(self.lua, n as ffi::lua_Integer) }
     }
 
     fn push_string(&mut self, s: &str) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushlstring(self.lua, s.as_ptr() as *const c_char, s.len() as size_t) }
     }
 
+    fn push_bytes(&mut self, b: &[u8]) {
+        self.ensure_stack(1);
+        unsafe { ffi::lua_pushlstring(self.lua, b.as_ptr() as *const c_char, b.len() as size_t) }
+    }
+
     fn push_boolean(&mut self, b: bool) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushboolean(self.lua, if b { 1 } else { 0 }) }
     }
 
     fn push_unsigned(&mut self, n: u64) {
+        self.ensure_stack(1);
         unsafe { ffi::lua_pushunsigned(self.lua, n as ffi::lua_Unsigned) }
     }
 
@@ -971,7 +1539,6 @@ impl State {
             if cstr.is_null() {
                 Err(RunError::conversion_from_lua(ty, "String", self.backtrace()))
             } else {
-                use std::slice;
                 Ok(try!(String::from_utf8(slice::from_raw_parts::<u8>(cstr as *const u8, len)
                         .to_vec())
                     .map_err(|_| RunError::conversion_from_lua(ty, "String", self.backtrace()))))
@@ -979,13 +1546,14 @@ impl State {
         }
     }
 
-    fn to_string_ptr(&mut self, idx: LuaIndex) -> RunResult<*const c_char> {
+    fn to_bytes(&mut self, idx: LuaIndex) -> RunResult<Vec<u8>> {
         unsafe {
-            let ptr = ffi::lua_tostring(self.lua, idx.to_ffi());
-            if ptr.is_null() {
-                Err(RunError::conversion_from_lua(self.type_at(idx), "usize", self.backtrace()))
+            let mut len: size_t = 0;
+            let cstr = ffi::lua_tolstring(self.lua, idx.to_ffi(), &mut len as *mut size_t);
+            if cstr.is_null() {
+                Err(RunError::conversion_from_lua(self.type_at(idx), "Vec<u8>", self.backtrace()))
             } else {
-                Ok(ptr)
+                Ok(slice::from_raw_parts::<u8>(cstr as *const u8, len).to_vec())
             }
         }
     }
@@ -1025,7 +1593,11 @@ impl State {
     fn lua_to_rust_load_result(&mut self, result: c_int) -> LoadResult<()> {
         match result {
             ffi::LUA_OK => Ok(()),
-            ffi::LUA_ERRSYNTAX => Err(LoadError::Syntax(self.at(LuaIndex::Stack(-1)).unwrap())),
+            ffi::LUA_ERRSYNTAX => {
+                let message: String = self.at(LuaIndex::Stack(-1)).unwrap();
+                self.pop(1);
+                Err(LoadError::Syntax(SyntaxError::parse(&message)))
+            }
             ffi::LUA_ERRMEM => panic!("Lua memory allocation error"),
             ffi::LUA_ERRERR => panic!("Lua error handler failed"),
             _ => unreachable!("{}", result),
@@ -1045,7 +1617,16 @@ impl State {
                     self.pop(1);
                     panic::resume_unwind(err);
                 } else {
-                    unreachable!()
+                    // Errors in finalizers bypass the message handler, and Lua replaces the error
+                    // object with a string describing it
+                    let message = self.at::<String>(LuaIndex::Stack(-1))
+                        .unwrap_or_else(|_| "unknown error".to_string());
+                    self.pop(1);
+                    if result == ffi::LUA_ERRGCMM {
+                        Err(RunError::finalizer(message))
+                    } else {
+                        Err(RunError::new(message, Vec::new()))
+                    }
                 }
             }
             ffi::LUA_ERRMEM => panic!("Lua memory allocation error"),
@@ -1055,17 +1636,181 @@ impl State {
     }
 }
 
+impl State {
+    /// Implementation detail of `lua_module!`; calls `open` with the arguments passed to the
+    /// `luaopen_*` function of a Lua C module and returns its results.
+    #[doc(hidden)]
+    pub unsafe fn open_module(lua: *mut ffi::lua_State, open: NativeFunction) -> c_int {
+        // Errors and panics are converted to a string, as the stock interpreter doesn't know what
+        // to make of our error userdata
+        raise_after_drop(lua, |state| {
+            let nargs = state.get_top() as u32;
+            state.push_function(open);
+            state.insert(AbsIndex(1));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                state.call(nargs, LuaCallResults::MultRet)
+            }));
+            let message = match result {
+                Ok(Ok(())) => return Ok(state.get_top() as c_int),
+                Ok(Err(err)) => {
+                    let mut message = err.message;
+                    if !err.backtrace.is_empty() {
+                        message.push_str("\nstack traceback:");
+                        for line in &err.backtrace {
+                            message.push_str("\n\t");
+                            message.push_str(line);
+                        }
+                    }
+                    message
+                }
+                Err(err) => format!("panic: {}", panic_message(&err)),
+            };
+            state.push_string(&message);
+            Err(())
+        })
+    }
+}
+
 impl Drop for State {
     fn drop(&mut self) {
-        unsafe {
-            if self.should_free {
+        if self.should_free {
+            // Let finalizers know that their errors will be ignored
+            self.get_internal_registry();
+            self.push_boolean(true);
+            self.set_field(LuaIndex::Stack(-2), "closing");
+            self.pop(1);
+            // Finalizers may still update the allocator's statistics while the state is closed
+            let allocator = self.allocator();
+            unsafe {
                 ffi::lua_close(self.lua);
+                if let Some(allocator) = allocator {
+                    drop(Box::from_raw(allocator));
+                }
+            }
+        }
+    }
+}
+
+/// The address of this static is used as the key of lowlua's table in the registry, as
+/// recommended by the Lua 5.3 reference manual.
+static REGISTRY_KEY: u8 = 0;
+
+// The function pushed by `push_closure()`, which calls the native function stored in upvalue 1.
+extern "C" fn call_native(lua: *mut ffi::lua_State) -> c_int {
+    raise_after_drop(lua, |state| {
+        let f = unsafe {
+            &*(ffi::lua_touserdata(lua, ffi::lua_upvalueindex(1)) as *mut NativeFunction)
+        };
+        let nargs = state.get_top();
+        // Call function and catch panics
+        let panic_result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut *state)));
+        match panic_result {
+            // No panic
+            Ok(result) => {
+                let result = if cfg!(debug_assertions) {
+                    result.and_then(|val| state.check_native_results(nargs, val))
+                } else {
+                    result
+                };
+                match result {
+                    Ok(val) => Ok(val as c_int),
+                    Err(err) => {
+                        state.push_userdata(err);
+                        Err(())
+                    }
+                }
+            }
+            // Panic!
+            Err(err) => {
+                state.push_userdata(err);
+                Err(())
             }
         }
+    })
+}
+
+/// Runs `f` in a native function called by Lua, and returns the number of results it returns,
+/// or raises the error value it pushed if it returns `Err`.
+///
+/// `lua_error()` jumps out of the native function without running destructors, so everything
+/// owned by Rust must be dropped before it is called. Keeping it all in `f`, which has returned
+/// by then, guarantees that.
+fn raise_after_drop<F>(lua: *mut ffi::lua_State, f: F) -> c_int
+    where F: FnOnce(&mut State) -> Result<c_int, ()>
+{
+    let result = {
+        let mut state = State::from_raw_state(lua);
+        f(&mut state)
+    };
+    match result {
+        Ok(nresults) => nresults,
+        Err(()) => unsafe { ffi::lua_error(lua) },
+    }
+}
+
+// Called to generate a backtrace on a Lua runtime error.
+extern "C" fn errfunc(lua: *mut ffi::lua_State) -> c_int {
+    // Coerce the error value into a RunError with a backtrace, unless it's a PanicError.
+    let mut state = State::from_raw_state(lua);
+    if state.is_userdata_of_type::<RunError>(LuaIndex::Stack(1)) ||
+       state.is_userdata_of_type::<Box<Any + Send>>(LuaIndex::Stack(1)) {
+        // do nothing
+    } else {
+        // Coerce to RunError
+        let message = match state.at::<String>(LuaIndex::Stack(1)) {
+            Ok(val) => val,
+            Err(_) => "unknown error".to_string(),
+        };
+        let bt = state.backtrace();
+        state.push_userdata(RunError::new(message, bt));
     }
+    1
 }
 
 // Miscellaneous private helper functions
+fn panic_message(err: &Box<Any + Send>) -> String {
+    if let Some(msg) = err.downcast_ref::<&'static str>() {
+        msg.to_string()
+    } else if let Some(msg) = err.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Converts a chunk name for the C API, which can't pass names containing NUL bytes.
+fn chunk_name(chunkname: &str) -> LoadResult<CString> {
+    CString::new(chunkname).map_err(|_| {
+        LoadError::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                     "chunk name contains a NUL byte"))
+    })
+}
+
+fn skip_file_prefix<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    // Byte order mark
+    let has_bom = try!(reader.fill_buf()).starts_with(b"\xEF\xBB\xBF");
+    if has_bom {
+        reader.consume(3);
+    }
+    // First line comment; the newline itself is kept so line numbers stay correct
+    if try!(reader.fill_buf()).first() == Some(&b'#') {
+        loop {
+            let (len, found) = {
+                let buf = try!(reader.fill_buf());
+                match buf.iter().position(|c| *c == b'\n') {
+                    Some(pos) => (pos, true),
+                    None => (buf.len(), buf.is_empty()),
+                }
+            };
+            reader.consume(len);
+            if found {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
 fn rust_to_lua_op(op: LuaOperator) -> c_int {
     match op {
         LuaOperator::Add => ffi::LUA_OPADD,
@@ -1085,6 +1830,14 @@ fn rust_to_lua_op(op: LuaOperator) -> c_int {
     }
 }
 
+fn rust_to_lua_compare(op: LuaCompare) -> c_int {
+    match op {
+        LuaCompare::Eq => ffi::LUA_OPEQ,
+        LuaCompare::Lt => ffi::LUA_OPLT,
+        LuaCompare::Le => ffi::LUA_OPLE,
+    }
+}
+
 fn lua_to_rust_type(typ: c_int) -> LuaType {
     match typ {
         ffi::LUA_TNIL => LuaType::Nil,
@@ -0,0 +1,109 @@
+use state::State;
+use state::traits::ToLua;
+use ::{LuaIndex, NativeFunction, AbsIndex};
+
+/// An entry of a module table.
+enum ModuleEntry {
+    Function(NativeFunction),
+    Constant(Box<ToLua>),
+    Submodule(ModuleBuilder),
+}
+
+/// Declaratively builds a table of native functions, constants and nested submodules, similar to
+/// `luaL_newlib` and `luaL_setfuncs` in the C API.
+///
+/// The table is created every time the builder is pushed, so a builder can be kept around and
+/// reused, for example from a native module loader registered with
+/// `State::add_native_module()`.
+///
+/// ```ignore
+/// let vector = ModuleBuilder::new()
+///     .function("new", vector_new)
+///     .function("length", vector_length)
+///     .constant("ZERO_LENGTH", 0.0);
+/// ModuleBuilder::new()
+///     .constant("VERSION", "1.0")
+///     .submodule("vector", vector)
+///     .set_global(&mut state, "geometry");
+/// ```
+pub struct ModuleBuilder {
+    entries: Vec<(String, ModuleEntry)>,
+}
+
+impl ModuleBuilder {
+    /// Creates an empty module.
+    pub fn new() -> ModuleBuilder {
+        ModuleBuilder { entries: Vec::new() }
+    }
+
+    /// Adds a native function to the module.
+    pub fn function(mut self, name: &str, f: NativeFunction) -> ModuleBuilder {
+        self.entries.push((name.to_string(), ModuleEntry::Function(f)));
+        self
+    }
+
+    /// Adds a constant value to the module.
+    pub fn constant<T: ToLua + 'static>(mut self, name: &str, value: T) -> ModuleBuilder {
+        self.entries.push((name.to_string(), ModuleEntry::Constant(Box::new(value))));
+        self
+    }
+
+    /// Adds a nested module, which is stored as a table in a field of this module.
+    pub fn submodule(mut self, name: &str, module: ModuleBuilder) -> ModuleBuilder {
+        self.entries.push((name.to_string(), ModuleEntry::Submodule(module)));
+        self
+    }
+
+    /// Creates the module table and pushes it onto the stack.
+    pub fn push(&self, state: &mut State) {
+        self.push_with_upvalues(state, 0);
+    }
+
+    /// Creates the module table and pushes it onto the stack, sharing the `nup` values at the top
+    /// of the stack as upvalues of every function in the module and its submodules, like
+    /// `luaL_setfuncs`. The upvalues are popped from the stack and can be accessed with
+    /// `LuaIndex::Upvalue(1)` to `LuaIndex::Upvalue(nup)`.
+    ///
+    /// Note that each function receives its own copy of the upvalues; a table shared this way
+    /// refers to the same object, but assigning a new value to an upvalue only affects one
+    /// function.
+    pub fn push_with_upvalues(&self, state: &mut State, nup: u32) {
+        let first = state.get_top() - nup as i32 + 1;
+        self.push_entries(state, first, nup);
+        if nup > 0 {
+            state.insert(AbsIndex(first));
+            state.set_top(first);
+        }
+    }
+
+    /// Creates the module table and sets it as the value of global `name`.
+    pub fn set_global(&self, state: &mut State, name: &str) {
+        self.push(state);
+        state.set_global(name);
+    }
+
+    fn push_entries(&self, state: &mut State, first_upvalue: i32, nup: u32) {
+        state.create_table(0, self.entries.len() as i32);
+        for &(ref name, ref entry) in &self.entries {
+            match *entry {
+                ModuleEntry::Function(f) => {
+                    for i in 0..nup as i32 {
+                        state.push_value(LuaIndex::Stack(first_upvalue + i));
+                    }
+                    state.push_closure(f, nup);
+                }
+                ModuleEntry::Constant(ref value) => value.to_lua(state),
+                ModuleEntry::Submodule(ref module) => {
+                    module.push_entries(state, first_upvalue, nup)
+                }
+            }
+            state.set_field(LuaIndex::Stack(-2), name);
+        }
+    }
+}
+
+impl ToLua for ModuleBuilder {
+    fn to_lua(&self, state: &mut State) {
+        self.push(state);
+    }
+}
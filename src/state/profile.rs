@@ -0,0 +1,328 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use state::{State, HookMask, HookEvent};
+use state::hook::HookGuard;
+
+/// How a `Profiler` collects data.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProfileMode {
+    /// Hooks every call and return to measure exact times and call counts. The hooks add a
+    /// noticeable overhead to every function call, which is included in the measured times.
+    Instrument,
+    /// Records the call stack after every `n` VM instructions. Times and call counts are not
+    /// measured, but the overhead is low and can be tuned with `n`.
+    Sample(u32),
+}
+
+/// Statistics about a function, identified by its source, the line where it was defined and its
+/// name, e.g. `game/ai.lua:12 think`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionStats {
+    /// The label of the function.
+    pub name: String,
+    /// The number of calls. Only measured in `ProfileMode::Instrument`.
+    pub calls: u64,
+    /// The time spent in the function, including the functions it called. For recursive
+    /// functions, every active call is counted. Only measured in `ProfileMode::Instrument`.
+    pub total_time: Duration,
+    /// The time spent in the function itself. Only measured in `ProfileMode::Instrument`.
+    pub self_time: Duration,
+    /// The number of samples in which the function was running. Only measured in
+    /// `ProfileMode::Sample`.
+    pub samples: u64,
+    /// The number of samples in which the function was on the call stack. Only measured in
+    /// `ProfileMode::Sample`.
+    pub total_samples: u64,
+}
+
+/// The results of a profiler run, returned by `Profiler::stop()`.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    mode: ProfileMode,
+    functions: Vec<FunctionStats>,
+    stacks: Vec<(String, u64)>,
+}
+
+impl Profile {
+    /// Returns the mode the profile was collected with.
+    pub fn mode(&self) -> ProfileMode {
+        self.mode
+    }
+
+    /// Returns the statistics of every function seen, most expensive first: by self time for
+    /// instrumented profiles, or by samples for sampled profiles.
+    pub fn functions(&self) -> &[FunctionStats] {
+        &self.functions
+    }
+
+    /// Returns the statistics of the function with the given label.
+    pub fn function(&self, name: &str) -> Option<&FunctionStats> {
+        self.functions.iter().find(|stats| stats.name == name)
+    }
+
+    /// Returns the profile in the collapsed stack format used by flame graph tools such as
+    /// `flamegraph.pl` and inferno. Each line contains a call stack, with frames separated by
+    /// semicolons and the outermost function first, followed by the self time in microseconds
+    /// for instrumented profiles or the number of samples for sampled profiles.
+    pub fn collapsed_stacks(&self) -> String {
+        let mut result = String::new();
+        for &(ref stack, weight) in &self.stacks {
+            if weight > 0 {
+                result.push_str(&format!("{} {}\n", stack, weight));
+            }
+        }
+        result
+    }
+
+    /// Returns a JSON summary of the profile, with the statistics of each function in the order
+    /// of `functions()`. Times are given in microseconds.
+    pub fn to_json(&self) -> String {
+        let mode = match self.mode {
+            ProfileMode::Instrument => "instrument",
+            ProfileMode::Sample(_) => "sample",
+        };
+        let functions: Vec<String> = self.functions
+            .iter()
+            .map(|stats| {
+                format!("{{\"name\":{},\"calls\":{},\"total_us\":{},\"self_us\":{},\
+                         \"samples\":{},\"total_samples\":{}}}",
+                        json_string(&stats.name),
+                        stats.calls,
+                        micros(stats.total_time),
+                        micros(stats.self_time),
+                        stats.samples,
+                        stats.total_samples)
+            })
+            .collect();
+        format!("{{\"mode\":\"{}\",\"functions\":[{}]}}", mode, functions.join(","))
+    }
+}
+
+/// A running profiler, created by `State::start_profiler()`.
+///
+/// Dropping the profiler without calling `stop()` discards the collected data and removes its
+/// hook.
+#[must_use]
+pub struct Profiler {
+    data: Rc<RefCell<ProfileData>>,
+    hook: HookGuard,
+}
+
+impl Profiler {
+    /// Stops profiling and returns the collected data. Calls which have not returned yet are not
+    /// included in instrumented profiles.
+    pub fn stop(self, state: &mut State) -> Profile {
+        state.remove_hook(self.hook.id());
+        let data = self.data.borrow();
+        let mut functions: Vec<FunctionStats> = data.functions.values().cloned().collect();
+        functions.sort_by(|a, b| {
+            b.self_time.cmp(&a.self_time).then(b.samples.cmp(&a.samples)).then(a.name.cmp(&b.name))
+        });
+        let mut stacks: Vec<(String, u64)> =
+            data.stacks.iter().map(|(stack, &weight)| (stack.clone(), weight)).collect();
+        stacks.sort();
+        Profile {
+            mode: data.mode,
+            functions: functions,
+            stacks: stacks,
+        }
+    }
+}
+
+/// An active call in an instrumented profile.
+struct Frame {
+    name: String,
+    start: Instant,
+    /// The time spent in functions called by this one.
+    child_time: Duration,
+    /// The number of active functions when the call started, including this one.
+    depth: u32,
+}
+
+struct ProfileData {
+    mode: ProfileMode,
+    functions: HashMap<String, FunctionStats>,
+    /// Weights by collapsed call stack.
+    stacks: HashMap<String, u64>,
+    frames: Vec<Frame>,
+}
+
+impl ProfileData {
+    fn stats(&mut self, name: &str) -> &mut FunctionStats {
+        self.functions.entry(name.to_string()).or_insert_with(|| {
+            FunctionStats {
+                name: name.to_string(),
+                calls: 0,
+                total_time: Duration::new(0, 0),
+                self_time: Duration::new(0, 0),
+                samples: 0,
+                total_samples: 0,
+            }
+        })
+    }
+
+    fn enter(&mut self, state: &mut State, now: Instant) {
+        // A frame at the same depth was replaced by a tail call, or left by an error
+        let depth = state.stack_depth();
+        self.unwind(depth, now);
+        if let Some(name) = frame_name(state, 0) {
+            self.stats(&name).calls += 1;
+            self.frames.push(Frame {
+                name: name,
+                start: now,
+                child_time: Duration::new(0, 0),
+                depth: depth,
+            });
+        }
+    }
+
+    fn leave(&mut self, state: &State, now: Instant) {
+        let depth = state.stack_depth();
+        self.unwind(depth + 1, now);
+        // The profiler may have been started inside a running function
+        if self.frames.last().map_or(false, |frame| frame.depth == depth) {
+            self.pop_frame(now);
+        }
+    }
+
+    /// Closes the frames at or above the given depth. Errors unwind the stack without return
+    /// hooks, so these functions are no longer running.
+    fn unwind(&mut self, depth: u32, now: Instant) {
+        while self.frames.last().map_or(false, |frame| frame.depth >= depth) {
+            self.pop_frame(now);
+        }
+    }
+
+    fn pop_frame(&mut self, now: Instant) {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let elapsed = now.duration_since(frame.start);
+        let self_time = elapsed.checked_sub(frame.child_time).unwrap_or(Duration::new(0, 0));
+        {
+            let stats = self.stats(&frame.name);
+            stats.total_time += elapsed;
+            stats.self_time += self_time;
+        }
+        let stack = {
+            let mut stack: Vec<&str> = self.frames.iter().map(|frame| &frame.name[..]).collect();
+            stack.push(&frame.name);
+            stack.join(";")
+        };
+        *self.stacks.entry(stack).or_insert(0) += micros(self_time);
+        if let Some(parent) = self.frames.last_mut() {
+            parent.child_time += elapsed;
+        }
+    }
+
+    fn sample(&mut self, state: &mut State) {
+        let mut stack = Vec::new();
+        let mut level = 0;
+        while let Some(name) = frame_name(state, level) {
+            stack.push(name);
+            level += 1;
+        }
+        if stack.is_empty() {
+            return;
+        }
+        stack.reverse();
+        self.stats(stack.last().unwrap()).samples += 1;
+        let mut seen: Vec<&String> = Vec::new();
+        for name in &stack {
+            if !seen.contains(&name) {
+                seen.push(name);
+                self.stats(name).total_samples += 1;
+            }
+        }
+        *self.stacks.entry(stack.join(";")).or_insert(0) += 1;
+    }
+}
+
+impl State {
+    /// Starts profiling the scripts running in this state. The profiler runs until
+    /// `Profiler::stop()` is called, and is built on `add_hook()`.
+    ///
+    /// ```ignore
+    /// let profiler = state.start_profiler(ProfileMode::Sample(1000));
+    /// state.do_file::<(), _>("game.lua").unwrap();
+    /// let profile = profiler.stop(&mut state);
+    /// File::create("game.folded").unwrap().write_all(profile.collapsed_stacks().as_bytes());
+    /// ```
+    pub fn start_profiler(&mut self, mode: ProfileMode) -> Profiler {
+        let data = Rc::new(RefCell::new(ProfileData {
+            mode: mode,
+            functions: HashMap::new(),
+            stacks: HashMap::new(),
+            frames: Vec::new(),
+        }));
+        let hook_data = data.clone();
+        let mask = match mode {
+            ProfileMode::Instrument => HookMask { call: true, ret: true, ..HookMask::default() },
+            ProfileMode::Sample(n) => HookMask { count: Some(n.max(1)), ..HookMask::default() },
+        };
+        let hook = self.add_guarded_hook(mask, move |state, event| {
+            let now = Instant::now();
+            let mut data = hook_data.borrow_mut();
+            match event {
+                // The caller of a tail call was replaced and will not return, which `enter()`
+                // handles like an error
+                HookEvent::Call | HookEvent::TailCall => data.enter(state, now),
+                HookEvent::Return => data.leave(state, now),
+                HookEvent::Count => data.sample(state),
+                HookEvent::Line(_) => {}
+            }
+            Ok(())
+        });
+        Profiler {
+            data: data,
+            hook: hook,
+        }
+    }
+}
+
+/// Returns the label of the function at the given stack level.
+fn frame_name(state: &mut State, level: u32) -> Option<String> {
+    let info = match state.with_stack_level(level, |state, ar| state.get_info(ar, "Sn")) {
+        Some(info) => info,
+        None => return None,
+    };
+    let name = match info.name {
+        Some(name) => name,
+        None if info.what == "main" => "main chunk".to_string(),
+        None => "?".to_string(),
+    };
+    // Semicolons separate frames in the collapsed stack format
+    let label = if info.what == "C" {
+        format!("[native] {}", name)
+    } else {
+        format!("{}:{} {}", info.short_src, info.line_defined, name)
+    };
+    Some(label.replace(';', ","))
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000 + duration.subsec_nanos() as u64 / 1000
+}
+
+/// Quotes and escapes a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
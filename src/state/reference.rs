@@ -0,0 +1,190 @@
+use std::rc::Rc;
+use std::cell::Cell;
+
+use state::{State, ReleaseQueue};
+use state::traits::ToLua;
+use ::{LuaType, LuaIndex, RelIndex};
+
+/// Which entries of a weak table may be collected, as set by `State::new_weak_table()`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WeakMode {
+    /// Entries are removed when their key is collected (`__mode = "k"`).
+    Keys,
+    /// Entries are removed when their value is collected (`__mode = "v"`).
+    Values,
+    /// Entries are removed when either their key or their value is collected (`__mode = "kv"`).
+    KeysAndValues,
+}
+
+/// Bookkeeping shared by a state and the references created from it.
+struct Refs {
+    /// Ids are never reused, so a weak reference never refers to a different value after its value
+    /// was collected.
+    next_id: Cell<i64>,
+    /// References dropped since the state last freed them, with `true` for weak references.
+    released: ReleaseQueue<(bool, i64)>,
+}
+
+/// A strong reference to a Lua value, which keeps the value alive as long as the handle exists.
+///
+/// Created by `State::create_ref()`. The value is stored in a table in lowlua's registry, and its
+/// slot is freed after the handle is dropped, the next time the state creates or resolves a
+/// reference. Handles may outlive their state, but must only be used with the state they were
+/// created from.
+pub struct LuaRef {
+    id: i64,
+    refs: Rc<Refs>,
+}
+
+impl Drop for LuaRef {
+    fn drop(&mut self) {
+        self.refs.released.release((false, self.id));
+    }
+}
+
+impl<'a> ToLua for &'a LuaRef {
+    fn to_lua(&self, state: &mut State) {
+        state.push_ref(self);
+    }
+}
+
+/// A weak reference to a Lua value, which does not keep the value alive.
+///
+/// Created by `State::create_weak_ref()` or `State::downgrade()`. The value is stored in a table
+/// with weak values in lowlua's registry, so it disappears once the garbage collector collects it.
+/// Values which are not collectable, such as numbers and strings, are never removed.
+pub struct WeakRef {
+    id: i64,
+    refs: Rc<Refs>,
+}
+
+impl WeakRef {
+    /// Returns a strong reference to the value, or `None` if it was collected.
+    pub fn upgrade(&self, state: &mut State) -> Option<LuaRef> {
+        if state.push_weak_ref(self) {
+            let strong = state.create_ref(LuaIndex::Stack(-1));
+            state.pop(1);
+            Some(strong)
+        } else {
+            state.pop(1);
+            None
+        }
+    }
+}
+
+impl Drop for WeakRef {
+    fn drop(&mut self) {
+        self.refs.released.release((true, self.id));
+    }
+}
+
+impl State {
+    /// Creates a strong reference to the value at the given index. The value is not popped.
+    pub fn create_ref(&mut self, idx: LuaIndex) -> LuaRef {
+        self.store_ref(idx, "strong", |id, refs| LuaRef { id: id, refs: refs })
+    }
+
+    /// Pushes the value a strong reference refers to onto the stack.
+    ///
+    /// Panics if the reference was created by a different state.
+    pub fn push_ref(&mut self, reference: &LuaRef) {
+        self.load_ref(reference.id, &reference.refs, "strong");
+    }
+
+    /// Creates a weak reference to the value at the given index. The value is not popped.
+    pub fn create_weak_ref(&mut self, idx: LuaIndex) -> WeakRef {
+        self.store_ref(idx, "weak", |id, refs| WeakRef { id: id, refs: refs })
+    }
+
+    /// Creates a weak reference to the value of a strong reference.
+    pub fn downgrade(&mut self, reference: &LuaRef) -> WeakRef {
+        self.push_ref(reference);
+        let weak = self.create_weak_ref(LuaIndex::Stack(-1));
+        self.pop(1);
+        weak
+    }
+
+    /// Pushes the value a weak reference refers to onto the stack, or `nil` if it was collected.
+    /// Returns `false` if the value was collected.
+    ///
+    /// Panics if the reference was created by a different state.
+    pub fn push_weak_ref(&mut self, reference: &WeakRef) -> bool {
+        self.load_ref(reference.id, &reference.refs, "weak");
+        !self.is_nil(LuaIndex::Stack(-1))
+    }
+
+    /// Creates a new empty table whose keys, values or both are weak references, and pushes it
+    /// onto the stack. Weak tables are useful for caches and for attaching data to objects
+    /// without keeping them alive.
+    pub fn new_weak_table(&mut self, mode: WeakMode) {
+        self.new_table();
+        self.create_table(0, 1);
+        self.push_string(match mode {
+            WeakMode::Keys => "k",
+            WeakMode::Values => "v",
+            WeakMode::KeysAndValues => "kv",
+        });
+        self.set_field(LuaIndex::Stack(-2), "__mode");
+        self.set_metatable(LuaIndex::Stack(-2));
+    }
+
+    fn store_ref<F, R>(&mut self, idx: LuaIndex, table: &str, f: F) -> R
+        where F: FnOnce(i64, Rc<Refs>) -> R
+    {
+        let idx = self.abs_index(idx);
+        let refs = self.get_refs();
+        let id = refs.next_id.get();
+        refs.next_id.set(id + 1);
+        self.get_field(LuaIndex::Stack(-1), table);
+        self.push_value(idx);
+        self.raw_set_i(LuaIndex::Stack(-2), id);
+        self.pop(2);
+        f(id, refs)
+    }
+
+    fn load_ref(&mut self, id: i64, owner: &Rc<Refs>, table: &str) {
+        let refs = self.get_refs();
+        assert!(Rc::ptr_eq(&refs, owner),
+                "reference used with a state other than the one that created it");
+        self.get_field(LuaIndex::Stack(-1), table);
+        self.raw_get_i(LuaIndex::Stack(-1), id);
+        self.remove(RelIndex(-2));
+        self.remove(RelIndex(-2));
+    }
+
+    /// Pushes the table of references, creating it if necessary, and frees the references which
+    /// were dropped since the last call.
+    fn get_refs(&mut self) -> Rc<Refs> {
+        self.get_internal_registry();
+        if self.get_field(LuaIndex::Stack(-1), "refs") != LuaType::Table {
+            self.pop(1);
+            // The refs table holds:
+            // * `strong`: Values of strong references by id.
+            // * `weak`: Values of weak references by id.
+            // * `handle`: The `Refs` shared with the handles.
+            self.new_table();
+            self.new_table();
+            self.set_field(LuaIndex::Stack(-2), "strong");
+            self.new_weak_table(WeakMode::Values);
+            self.set_field(LuaIndex::Stack(-2), "weak");
+            self.push_userdata(Rc::new(Refs {
+                next_id: Cell::new(1),
+                released: ReleaseQueue::new(),
+            }));
+            self.set_field(LuaIndex::Stack(-2), "handle");
+            self.push_value(LuaIndex::Stack(-1));
+            self.set_field(LuaIndex::Stack(-3), "refs");
+        }
+        self.remove(RelIndex(-2));
+        self.get_field(LuaIndex::Stack(-1), "handle");
+        let refs = self.userdata_at::<Rc<Refs>>(LuaIndex::Stack(-1)).unwrap().clone();
+        self.pop(1);
+        for (weak, id) in refs.released.take() {
+            self.get_field(LuaIndex::Stack(-1), if weak { "weak" } else { "strong" });
+            self.push_nil();
+            self.raw_set_i(LuaIndex::Stack(-2), id);
+            self.pop(1);
+        }
+        refs
+    }
+}
@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use state::State;
+use ::{RunResult, RunError, LuaType, LuaIndex, AbsIndex, RelIndex, LoadMode, NativeFunction};
+
+impl State {
+    /// Adds a native function to the end of `package.searchers`, which `require` uses to find
+    /// modules. Returns an error if the package library is not open.
+    ///
+    /// A searcher is called with the module name as its only argument. If it finds the module, it
+    /// returns a loader function and an extra value which is passed to the loader; otherwise it
+    /// returns a string explaining where it looked, such as `"\n\tno file 'x.lua'"`. The value
+    /// returned by the loader is stored in `package.loaded` by `require`.
+    pub fn add_searcher(&mut self, f: NativeFunction) -> RunResult<()> {
+        try!(self.get_searchers());
+        let n = self.raw_len(LuaIndex::Stack(-1)) as i64;
+        self.push_function(f);
+        self.raw_set_i(LuaIndex::Stack(-2), n + 1);
+        self.pop(1);
+        Ok(())
+    }
+
+    /// Registers the source code or bytecode of a Lua module with `require`, so that
+    /// `require(name)` loads it from memory instead of searching the file system. Returns an
+    /// error if the package library is not open.
+    ///
+    /// The chunk is only loaded when the module is first required. Its chunk name is derived from
+    /// the module name, e.g. `@ai/pathing.lua` for `ai.pathing`. Since the chunk is supplied by the
+    /// host, binary chunks are accepted.
+    pub fn add_module_source<B: AsRef<[u8]>>(&mut self, name: &str, chunk: B) -> RunResult<()> {
+        try!(self.get_module_registry());
+        self.get_field(LuaIndex::Stack(-1), "sources");
+        self.push_bytes(chunk.as_ref());
+        self.set_field(LuaIndex::Stack(-2), name);
+        self.pop(2);
+        Ok(())
+    }
+
+    /// Adds a directory to search for Lua modules. `require("ai.pathing")` looks for
+    /// `root/ai/pathing.lua` and `root/ai/pathing/init.lua`, independently of `package.path`.
+    /// Returns an error if the package library is not open.
+    ///
+    /// Directories are searched in the order they are added, after modules registered with
+    /// `add_native_module()` and `add_module_source()`. Only text chunks are accepted.
+    pub fn add_module_root<P: AsRef<Path>>(&mut self, root: P) -> RunResult<()> {
+        let root = match root.as_ref().to_str() {
+            Some(root) => root.to_string(),
+            None => {
+                return Err(RunError::new("module root is not valid UTF-8".to_string(),
+                                         self.backtrace()))
+            }
+        };
+        try!(self.get_module_registry());
+        self.get_field(LuaIndex::Stack(-1), "roots");
+        let n = self.raw_len(LuaIndex::Stack(-1)) as i64;
+        self.push_string(&root);
+        self.raw_set_i(LuaIndex::Stack(-2), n + 1);
+        self.pop(2);
+        Ok(())
+    }
+
+    /// Registers a native module with `require`. When the module is first required, `open` is
+    /// called with the module name as its first argument and should return the module's value,
+    /// usually a table of functions. Returns an error if the package library is not open.
+    pub fn add_native_module(&mut self, name: &str, open: NativeFunction) -> RunResult<()> {
+        try!(self.get_module_registry());
+        self.get_field(LuaIndex::Stack(-1), "native");
+        self.push_function(open);
+        self.set_field(LuaIndex::Stack(-2), name);
+        self.pop(2);
+        Ok(())
+    }
+
+    /// Pushes `package.searchers` onto the stack, or returns an error and pushes nothing if the
+    /// package library is not open.
+    fn get_searchers(&mut self) -> RunResult<()> {
+        let top = self.get_top();
+        if self.get_global("package") == LuaType::Table &&
+           self.get_field(LuaIndex::Stack(-1), "searchers") == LuaType::Table {
+            self.remove(RelIndex(-2));
+            Ok(())
+        } else {
+            self.set_top(top);
+            Err(RunError::new("the package library is not open".to_string(), self.backtrace()))
+        }
+    }
+
+    /// Pushes the table of modules registered from Rust onto the stack. The first call creates the
+    /// table and installs the searcher which looks modules up in it.
+    fn get_module_registry(&mut self) -> RunResult<()> {
+        self.get_internal_registry();
+        if self.get_field(LuaIndex::Stack(-1), "modules") == LuaType::Table {
+            self.remove(RelIndex(-2));
+            return Ok(());
+        }
+        self.pop(1);
+        if let Err(err) = self.get_searchers() {
+            self.pop(1);
+            return Err(err);
+        }
+        // Insert our searcher right after the `package.preload` searcher, so modules registered
+        // from Rust take precedence over files found through `package.path`
+        let n = self.raw_len(LuaIndex::Stack(-1)) as i64;
+        let mut i = n;
+        while i >= 2 {
+            self.raw_get_i(LuaIndex::Stack(-1), i);
+            self.raw_set_i(LuaIndex::Stack(-2), i + 1);
+            i -= 1;
+        }
+        self.push_function(module_searcher);
+        self.raw_set_i(LuaIndex::Stack(-2), if n >= 1 { 2 } else { 1 });
+        self.pop(1);
+        // Create the module registry:
+        // * `native`: A table that maps module names to native loader functions.
+        // * `sources`: A table that maps module names to chunks.
+        // * `roots`: A sequence of directories to search.
+        self.new_table();
+        self.new_table();
+        self.set_field(LuaIndex::Stack(-2), "native");
+        self.new_table();
+        self.set_field(LuaIndex::Stack(-2), "sources");
+        self.new_table();
+        self.set_field(LuaIndex::Stack(-2), "roots");
+        self.push_value(LuaIndex::Stack(-1));
+        self.set_field(LuaIndex::Stack(-3), "modules");
+        self.remove(RelIndex(-2));
+        Ok(())
+    }
+}
+
+// The searcher installed into `package.searchers`.
+fn module_searcher(state: &mut State) -> RunResult<u32> {
+    let name: String = try!(state.at(LuaIndex::Stack(1)));
+    state.get_internal_registry();
+    state.get_field(LuaIndex::Stack(-1), "modules");
+    let modules = state.get_top();
+
+    // Native modules
+    state.get_field(LuaIndex::Stack(modules), "native");
+    if state.get_field(LuaIndex::Stack(-1), &name) == LuaType::Function {
+        state.push_string(&format!(":native:{}", name));
+        return Ok(leave_results(state, 2));
+    }
+    state.pop(2);
+
+    // Embedded sources
+    let chunkname = format!("@{}.lua", name.replace('.', "/"));
+    state.get_field(LuaIndex::Stack(modules), "sources");
+    if state.get_field(LuaIndex::Stack(-1), &name) == LuaType::String {
+        let chunk = try!(state.to_bytes(LuaIndex::Stack(-1)));
+        return match state.load_bytes(&chunk, &chunkname, LoadMode::Both) {
+            Ok(()) => {
+                state.push_string(&chunkname[1..]);
+                Ok(leave_results(state, 2))
+            }
+            Err(err) => {
+                Err(RunError::new(format!("error loading module '{}' from embedded source \
+                                           '{}':\n\t{}",
+                                          name,
+                                          &chunkname[1..],
+                                          err),
+                                  state.backtrace()))
+            }
+        };
+    }
+    state.pop(2);
+    let mut message = format!("\n\tno embedded module '{}'", name);
+
+    // Directory roots
+    state.get_field(LuaIndex::Stack(modules), "roots");
+    let nroots = state.raw_len(LuaIndex::Stack(-1)) as i64;
+    let relative = name.replace('.', "/");
+    for i in 1..nroots + 1 {
+        state.raw_get_i(LuaIndex::Stack(-1), i);
+        let root: String = try!(state.at(LuaIndex::Stack(-1)));
+        state.pop(1);
+        for candidate in &[format!("{}/{}.lua", root, relative),
+                           format!("{}/{}/init.lua", root, relative)] {
+            if !Path::new(candidate).is_file() {
+                message.push_str(&format!("\n\tno file '{}'", candidate));
+                continue;
+            }
+            return match state.load_file(candidate, LoadMode::Text) {
+                Ok(()) => {
+                    state.push_string(candidate);
+                    Ok(leave_results(state, 2))
+                }
+                Err(err) => {
+                    Err(RunError::new(format!("error loading module '{}' from file '{}':\n\t{}",
+                                              name,
+                                              candidate,
+                                              err),
+                                      state.backtrace()))
+                }
+            };
+        }
+    }
+    state.push_string(&message);
+    Ok(leave_results(state, 1))
+}
+
+/// Moves the `n` values at the top of the stack to the bottom and discards everything else, so
+/// that the searcher leaves nothing but its results.
+fn leave_results(state: &mut State, n: u32) -> u32 {
+    state.rotate(AbsIndex(1), n as i32);
+    state.set_top(n as i32);
+    n
+}
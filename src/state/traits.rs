@@ -1,6 +1,6 @@
 use std::convert::TryFrom;
 use state::State;
-use ::{RunResult, RunError, LuaIndex, LuaString};
+use ::{RunResult, RunError, LuaIndex};
 
 /// A conversion of a type into a Lua representation.
 ///
@@ -22,6 +22,17 @@ pub trait FromLua: Sized {
     fn from_lua(state: &mut State, idx: LuaIndex) -> RunResult<Self>;
 }
 
+/// A conversion from a sequence of values on the Lua stack into a native type. This is used to
+/// convert the results of functions, which may return any number of values.
+///
+/// `idx` is the absolute index of the first value and `n` is the number of values available.
+/// Missing values are converted as if they were `nil`, and extra values are ignored. Every type
+/// implementing `FromLua` converts the first value, `()` ignores all values, and tuples convert
+/// one value per element.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(state: &mut State, idx: i32, n: i32) -> RunResult<Self>;
+}
+
 // Some standard implementations of the traits follow
 
 // To
@@ -115,17 +126,6 @@ impl<'a> ToLua for String {
     }
 }
 
-impl ToLua for LuaString {
-    fn to_lua(&self, state: &mut State) {
-        state.get_internal_registry();
-        state.get_field(LuaIndex::Stack(-1), "string");
-        state.remove(-2);
-        state.push(self.0);
-        state.raw_get(LuaIndex::Stack(-2));
-        state.remove(-2);
-    }
-}
-
 // From
 impl FromLua for u8 {
     fn from_lua(state: &mut State, idx: LuaIndex) -> RunResult<u8> {
@@ -220,16 +220,52 @@ impl FromLua for String {
     }
 }
 
-impl FromLua for LuaString {
-    fn from_lua(state: &mut State, idx: LuaIndex) -> RunResult<LuaString> {
-        let val = try!(state.to_string_ptr(idx)) as usize;
-        // Record a reference to the string
-        state.get_internal_registry();
-        state.get_field(LuaIndex::Stack(-1), "string");
-        state.push(val);
-        state.push_value(idx);
-        state.raw_set(LuaIndex::Stack(-3));
-        state.pop(2);
-        Ok(LuaString(val))
+// FromMulti
+impl FromLuaMulti for () {
+    fn from_lua_multi(_state: &mut State, _idx: i32, _n: i32) -> RunResult<()> {
+        Ok(())
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(state: &mut State, idx: i32, n: i32) -> RunResult<T> {
+        nth_from_lua(state, idx, n, 0)
+    }
+}
+
+macro_rules! impl_from_lua_multi_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            #[allow(unused_assignments)]
+            fn from_lua_multi(state: &mut State, idx: i32, n: i32) -> RunResult<($($name,)+)> {
+                let mut i = 0;
+                Ok(($({
+                    let val = try!(nth_from_lua::<$name>(state, idx, n, i));
+                    i += 1;
+                    val
+                },)+))
+            }
+        }
+    }
+}
+
+impl_from_lua_multi_tuple!(A);
+impl_from_lua_multi_tuple!(A, B);
+impl_from_lua_multi_tuple!(A, B, C);
+impl_from_lua_multi_tuple!(A, B, C, D);
+impl_from_lua_multi_tuple!(A, B, C, D, E);
+impl_from_lua_multi_tuple!(A, B, C, D, E, F);
+impl_from_lua_multi_tuple!(A, B, C, D, E, F, G);
+impl_from_lua_multi_tuple!(A, B, C, D, E, F, G, H);
+
+// Converts the `i`th of `n` values starting at `idx`, or `nil` if there are not enough values.
+fn nth_from_lua<T: FromLua>(state: &mut State, idx: i32, n: i32, i: i32) -> RunResult<T> {
+    if i < n {
+        state.at(LuaIndex::Stack(idx + i))
+    } else {
+        state.push_nil();
+        let result = state.at(LuaIndex::Stack(-1));
+        state.pop(1);
+        result
     }
 }